@@ -0,0 +1,244 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+};
+
+use axum::Router;
+use thiserror::Error;
+use tokio::{net::TcpListener, runtime::Handle};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tower::make::Shared;
+
+use crate::{
+    build_service::{BuildService, BuildServiceHandle},
+    config::Config,
+    config_watcher::ConfigWatcher,
+    editor_protocol::{EditorServer, EditorServiceWrapper},
+    editor_service::EditorService,
+    http_service::router,
+    metrics::Metrics,
+    notes_service::{NotesService, NotesServiceHandle},
+    package::migrate_downloaded_packages,
+};
+
+pub const DEFAULT_HTTP_ADDRESS: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3000));
+pub const DEFAULT_EDITOR_ADDRESS: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3001));
+
+/// The whole phelps stack: the notes and build services, the editor server
+/// and the HTTP server. Created with [`Phelps::builder`], and running until
+/// [`Phelps::run`]'s shutdown future completes.
+pub struct Phelps {
+    notes_service_handle: NotesServiceHandle,
+    build_service_handle: BuildServiceHandle,
+    notes_service: NotesService,
+    build_service: BuildService,
+    config_watcher: Option<ConfigWatcher>,
+    http_listener: TcpListener,
+    editor_listener: TcpListener,
+    router: Router,
+    cancel: CancellationToken,
+    /// Handed to the notes and build services, which only cancel it themselves
+    /// when they give up on the build directory.
+    services: CancellationToken,
+}
+
+pub struct PhelpsBuilder {
+    config: Config,
+    http_listener: Option<TcpListener>,
+    editor_listener: Option<TcpListener>,
+    routes: Option<Router>,
+}
+
+#[derive(Debug, Error)]
+pub enum PhelpsError {
+    #[error("couldn't migrate downloaded packages: {0}")]
+    Migrate(io::Error),
+    #[error("couldn't start the file watcher: {0}")]
+    Watcher(#[from] notify_debouncer_full::notify::Error),
+    #[error("couldn't bind {0}: {1}")]
+    Bind(SocketAddr, io::Error),
+}
+
+/// Why [`Phelps::run`] returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stopped {
+    /// The shutdown future completed.
+    Shutdown,
+    /// The services gave up because the build directory couldn't be written
+    /// to.
+    BuildDirectoryFailure,
+}
+
+impl PhelpsBuilder {
+    /// Serves the HTTP API from this listener instead of the config's
+    /// `http_address`.
+    pub fn http_listener(mut self, listener: TcpListener) -> Self {
+        self.http_listener = Some(listener);
+        self
+    }
+
+    /// Serves the editor protocol from this listener instead of the config's
+    /// `editor_address`.
+    pub fn editor_listener(mut self, listener: TcpListener) -> Self {
+        self.editor_listener = Some(listener);
+        self
+    }
+
+    /// Serves these routes alongside the HTTP API. They must not overlap with
+    /// its routes.
+    pub fn routes(mut self, routes: Router) -> Self {
+        self.routes = Some(routes);
+        self
+    }
+
+    /// Creates the services and binds the listeners which weren't given.
+    /// Nothing runs until [`Phelps::run`] is called. Must be called within a
+    /// Tokio runtime.
+    pub async fn build(self) -> Result<Phelps, PhelpsError> {
+        let Self {
+            config,
+            http_listener,
+            editor_listener,
+            routes,
+        } = self;
+
+        migrate_downloaded_packages(&config.data_directory, &config.cache_directory)
+            .map_err(PhelpsError::Migrate)?;
+
+        let http_listener = match http_listener {
+            Some(listener) => listener,
+            None => TcpListener::bind(config.http_address)
+                .await
+                .map_err(|error| PhelpsError::Bind(config.http_address, error))?,
+        };
+        let editor_listener = match editor_listener {
+            Some(listener) => listener,
+            None => TcpListener::bind(config.editor_address)
+                .await
+                .map_err(|error| PhelpsError::Bind(config.editor_address, error))?,
+        };
+
+        let cancel = CancellationToken::new();
+        let services = cancel.child_token();
+        let metrics = Arc::new(Metrics::default());
+        let (notes_service_handle, notes_service) = NotesServiceHandle::build(
+            services.clone(),
+            config.build_subdirectory.clone(),
+            config.project_directory.clone(),
+            config.default_note,
+        );
+        let (build_service_handle, build_service) = BuildService::try_build(
+            config.clone(),
+            Handle::current(),
+            notes_service_handle.clone(),
+            metrics.clone(),
+            services.clone(),
+        )?;
+        let config_watcher = ConfigWatcher::try_build(
+            config,
+            notes_service_handle.clone(),
+            build_service_handle.clone(),
+            cancel.clone(),
+        )?;
+
+        let mut router = router(
+            notes_service_handle.clone(),
+            build_service_handle.clone(),
+            metrics,
+        );
+        if let Some(routes) = routes {
+            router = router.merge(routes);
+        }
+
+        Ok(Phelps {
+            notes_service_handle,
+            build_service_handle,
+            notes_service,
+            build_service,
+            config_watcher,
+            http_listener,
+            editor_listener,
+            router,
+            cancel,
+            services,
+        })
+    }
+}
+
+impl Phelps {
+    pub fn builder(config: Config) -> PhelpsBuilder {
+        PhelpsBuilder {
+            config,
+            http_listener: None,
+            editor_listener: None,
+            routes: None,
+        }
+    }
+
+    pub fn notes_service(&self) -> &NotesServiceHandle {
+        &self.notes_service_handle
+    }
+
+    pub fn build_service(&self) -> &BuildServiceHandle {
+        &self.build_service_handle
+    }
+
+    pub fn http_address(&self) -> io::Result<SocketAddr> {
+        self.http_listener.local_addr()
+    }
+
+    pub fn editor_address(&self) -> io::Result<SocketAddr> {
+        self.editor_listener.local_addr()
+    }
+
+    /// Runs every service until `shutdown` completes or the services give up,
+    /// then waits for them to stop.
+    pub async fn run(self, shutdown: impl Future<Output = ()>) -> Stopped {
+        let Self {
+            notes_service_handle,
+            build_service_handle,
+            notes_service,
+            build_service,
+            config_watcher,
+            http_listener,
+            editor_listener,
+            router,
+            cancel,
+            services,
+        } = self;
+        let tracker = TaskTracker::new();
+
+        tracker.spawn(build_service.run());
+        tracker.spawn(notes_service.run());
+        if let Some(config_watcher) = config_watcher {
+            tracker.spawn(config_watcher.run());
+        }
+
+        let editor_service = Shared::new(EditorServiceWrapper(EditorService::new(
+            notes_service_handle,
+            build_service_handle,
+        )));
+        let editor = EditorServer::new(editor_listener, editor_service, cancel.clone());
+
+        tracker.spawn(editor.run());
+
+        let http = axum::serve(http_listener, router)
+            .with_graceful_shutdown(cancel.clone().cancelled_owned())
+            .into_future();
+
+        tracker.spawn(http);
+        tracker.close();
+
+        let stopped = tokio::select! {
+            _ = shutdown => Stopped::Shutdown,
+            _ = services.cancelled() => Stopped::BuildDirectoryFailure,
+        };
+        cancel.cancel();
+        tracker.wait().await;
+
+        stopped
+    }
+}
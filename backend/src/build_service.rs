@@ -1,15 +1,15 @@
 use std::{
     collections::{HashMap, HashSet},
     error::Error,
-    io,
+    io, mem,
     path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use bytes::Buf;
-use ego_tree::{NodeRef, Tree};
+use ego_tree::{NodeId, NodeRef, Tree};
 use http_body_util::Empty;
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use hyper_util::{
@@ -18,32 +18,48 @@ use hyper_util::{
 };
 use markup5ever::{Attribute, LocalName, QualName, ns};
 use notify_debouncer_full::{
-    DebounceEventHandler, DebounceEventResult, Debouncer, RecommendedCache, new_debouncer,
+    DebounceEventHandler, DebounceEventResult, DebouncedEvent, Debouncer, RecommendedCache,
+    new_debouncer,
     notify::{self, RecommendedWatcher},
 };
 use parking_lot::Mutex;
-use petgraph::{Direction, prelude::DiGraphMap, visit::Bfs};
+use petgraph::{Direction, algo::tarjan_scc, prelude::DiGraphMap};
 use scraper::{
     ElementRef, Html, Node, Selector, StrTendril,
     node::{Element, Text},
 };
-use tokio::{fs, runtime::Handle, sync::mpsc};
+use thiserror::Error;
+use tokio::{
+    fs,
+    runtime::Handle,
+    sync::{mpsc, oneshot},
+};
 use tokio_util::sync::CancellationToken;
+use tracing::{Span, debug, error, info};
 use typst::{
     Document,
     diag::{PackageError, SourceDiagnostic, Warned},
     ecow::EcoVec,
+    foundations::{Label, Smart, StyleChain, Value},
+    introspection::MetadataElem,
     model::HeadingElem,
     syntax::{FileId, VirtualPath},
+    utils::PicoStr,
 };
 use typst_html::HtmlDocument;
 use uuid::Uuid;
-use walkdir::{DirEntry, WalkDir};
 
 use crate::{
-    notes_service::{NoteData, NotesServiceHandle},
+    config::{Config, NoteConfig, SourceGlobs, SubNotes},
+    dependency_graph::DependencyGraph,
+    file_source::{DiskFileSource, FileSource},
+    graph_export::FileGraph,
+    metrics::Metrics,
+    notes_service::{BuildResult, NoteData, NoteMetadata, NotesServiceHandle, OutlineEntry},
     package::{ClientWrapper, HttpWrapper, PackageService, PackageStorage},
-    system_world::{FileSlot, Resources, SystemWorld},
+    retry::{MAX_CONSECUTIVE_FAILURES, retry},
+    source_filter::SourceFilter,
+    system_world::{FileSlot, Overlay, OverlayError, Resources, SystemWorld, TextChange},
 };
 
 pub struct MpscWrapper(pub mpsc::Sender<DebounceEventResult>);
@@ -54,40 +70,27 @@ impl DebounceEventHandler for MpscWrapper {
     }
 }
 
-pub struct BuildService {
-    project_directory: PathBuf,
-    source_directories: Vec<PathBuf>,
-    build_subdirectory: Arc<PathBuf>,
-    package_storage: PackageStorage<
-        HttpWrapper<ClientWrapper<HttpsConnector<HttpConnector>, Empty<hyper::body::Bytes>>>,
-    >,
-    resources: Arc<Resources>,
-    slots: Arc<Mutex<HashMap<FileId, FileSlot>>>,
-    is_source: HashSet<FileId>,
-    notes_service: NotesServiceHandle,
-    receiver: mpsc::Receiver<DebounceEventResult>,
-    watcher: Debouncer<RecommendedWatcher, RecommendedCache>,
-    cancel: CancellationToken,
-    graph: DiGraphMap<FileId, ()>,
+/// Downloads packages from the Typst package registry.
+pub type RegistryPackageService =
+    HttpWrapper<ClientWrapper<HttpsConnector<HttpConnector>, Empty<hyper::body::Bytes>>>;
+
+/// Where the build service gets files, file events and packages from.
+pub struct BuildInputs<S> {
+    pub files: Arc<dyn FileSource>,
+    /// Debounced batches of file events, usually sent by `watcher`.
+    pub events: mpsc::Receiver<DebounceEventResult>,
+    /// Started on the project directory once the initial build is done.
+    pub watcher: Option<Debouncer<RecommendedWatcher, RecommendedCache>>,
+    pub package_service: S,
 }
 
-impl BuildService {
-    // No it doesn't
-    #[allow(clippy::too_many_arguments)]
-    pub fn try_build(
-        project_directory: PathBuf,
-        source_directories: Vec<PathBuf>,
-        build_subdirectory: PathBuf,
-        cache_directory: PathBuf,
-        data_directory: PathBuf,
-        handle: Handle,
-        notes_service: NotesServiceHandle,
-        cancel: CancellationToken,
-    ) -> Result<Self, notify::Error> {
-        const BUFFER_SIZE: usize = 128;
+impl BuildInputs<RegistryPackageService> {
+    /// Reads and watches files on disk, and downloads packages from the
+    /// registry.
+    pub fn system() -> Result<Self, notify::Error> {
         const DEBOUNCE_TIMEOUT: Duration = Duration::from_millis(500);
 
-        let (sender, receiver) = mpsc::channel(BUFFER_SIZE);
+        let (sender, events) = mpsc::channel(BUFFER_SIZE);
 
         let https = HttpsConnectorBuilder::new()
             .with_native_roots()?
@@ -99,52 +102,207 @@ impl BuildService {
         // generic for `request`?
         let client: Client<_, Empty<hyper::body::Bytes>> =
             Client::builder(TokioExecutor::new()).build(https);
-        let service = HttpWrapper(ClientWrapper(client));
-        let package_storage =
-            PackageStorage::new(cache_directory, data_directory, handle.clone(), service);
-        let resources = Arc::new(Resources::new(project_directory.clone()));
-        let slots = Arc::new(Mutex::new(HashMap::new()));
-
-        let graph = DiGraphMap::new();
-        let is_source = HashSet::new();
 
         // let watcher = RecommendedWatcher::new(MpscWrapper(sender), Default::default())?;
         let watcher = new_debouncer(DEBOUNCE_TIMEOUT, None, MpscWrapper(sender))?;
 
         Ok(Self {
+            files: Arc::new(DiskFileSource),
+            events,
+            watcher: Some(watcher),
+            package_service: HttpWrapper(ClientWrapper(client)),
+        })
+    }
+}
+
+const BUFFER_SIZE: usize = 128;
+
+pub struct BuildService<S = RegistryPackageService> {
+    project_directory: PathBuf,
+    source_directories: Vec<PathBuf>,
+    sources: SourceFilter,
+    build_subdirectory: Arc<PathBuf>,
+    files: Arc<dyn FileSource>,
+    package_storage: PackageStorage<S>,
+    resources: Arc<Resources>,
+    slots: Arc<Mutex<HashMap<FileId, FileSlot>>>,
+    overlay: Overlay,
+    note_config: NoteConfig,
+    notes_service: NotesServiceHandle,
+    metrics: Arc<Metrics>,
+    receiver: mpsc::Receiver<DebounceEventResult>,
+    /// File changes which arrived during a rebuild and still need handling.
+    deferred: Vec<FileChange>,
+    /// Settings which changed during a rebuild and still need applying.
+    reconfiguration: Option<Reconfiguration>,
+    /// Whether ignore rules changed, so that files might have become or
+    /// stopped being sources.
+    rescan: bool,
+    messages: mpsc::Receiver<BuildMessage>,
+    watcher: Option<Debouncer<RecommendedWatcher, RecommendedCache>>,
+    cancel: CancellationToken,
+    /// Builds in a row whose notes all failed to be written.
+    failed_builds: usize,
+    dependencies: DependencyGraph,
+    /// Rendered fragments before transclusions are expanded.
+    fragments: Arc<Mutex<HashMap<Uuid, String>>>,
+    /// Edges go from a note to the notes that transclude it.
+    transclusions: DiGraphMap<Uuid, ()>,
+    notes: HashMap<FileId, Vec<Uuid>>,
+    note_files: HashMap<Uuid, FileId>,
+}
+
+impl BuildService {
+    pub fn try_build(
+        config: Config,
+        handle: Handle,
+        notes_service: NotesServiceHandle,
+        metrics: Arc<Metrics>,
+        cancel: CancellationToken,
+    ) -> Result<(BuildServiceHandle, Self), notify::Error> {
+        Ok(Self::new(
+            config,
+            handle,
+            notes_service,
+            metrics,
+            cancel,
+            BuildInputs::system()?,
+        ))
+    }
+}
+
+impl<S> BuildService<S>
+where
+    S: PackageService + Clone + Send + Sync + 'static,
+    PackageError: From<S::GetIndexServiceError>,
+    PackageError: From<S::GetPackageServiceError>,
+    S::GetPackageBuffer: Buf,
+{
+    /// Creates a build service reading from the given inputs, which don't have
+    /// to be the disk and the network.
+    pub fn new(
+        config: Config,
+        handle: Handle,
+        notes_service: NotesServiceHandle,
+        metrics: Arc<Metrics>,
+        cancel: CancellationToken,
+        BuildInputs {
+            files,
+            events: receiver,
+            watcher,
+            package_service,
+        }: BuildInputs<S>,
+    ) -> (BuildServiceHandle, Self) {
+        let (message_sender, messages) = mpsc::channel(BUFFER_SIZE);
+
+        let source_directories = config.source_directories();
+        let resources = Arc::new(Resources::from_config(&config));
+        let Config {
+            data_directory,
+            cache_directory,
+            project_directory,
+            build_subdirectory,
+            notes: note_config,
+            sources: source_globs,
+            ..
+        } = config;
+
+        let package_storage = PackageStorage::new(
+            cache_directory,
+            data_directory,
+            handle.clone(),
+            package_service,
+            metrics.clone(),
+        );
+        let slots = Arc::new(Mutex::new(HashMap::new()));
+
+        let sources = SourceFilter::new(
+            files.as_ref(),
+            project_directory.clone(),
+            source_directories.clone(),
+            build_subdirectory.clone(),
+            source_globs,
+        );
+
+        let service = Self {
             receiver,
+            deferred: Vec::new(),
+            reconfiguration: None,
+            rescan: false,
+            messages,
             project_directory,
             source_directories,
+            sources,
             build_subdirectory: Arc::new(build_subdirectory),
+            files,
             package_storage,
             resources,
             slots,
-            is_source,
+            overlay: Overlay::default(),
+            note_config,
             notes_service,
+            metrics,
             watcher,
             cancel,
-            graph,
-        })
+            failed_builds: 0,
+            dependencies: DependencyGraph::default(),
+            fragments: Arc::default(),
+            transclusions: DiGraphMap::new(),
+            notes: HashMap::new(),
+            note_files: HashMap::new(),
+        };
+        let handle = BuildServiceHandle {
+            sender: message_sender,
+        };
+
+        (handle, service)
     }
 
-    pub async fn start(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Builds every source file from scratch.
+    pub async fn build_all(&mut self) -> Result<(), Box<dyn Error>> {
         if self.build_subdirectory.exists() {
             fs::remove_dir_all(self.build_subdirectory.as_ref()).await?;
         }
         fs::create_dir(self.build_subdirectory.as_ref()).await?;
 
+        let paths = self.scan_sources().await;
+
+        let start = Instant::now();
+        let mut seen = HashSet::new();
+
+        for (path, virtual_path) in paths {
+            if seen.insert(path.clone()) {
+                let id = FileId::new(None, virtual_path);
+
+                let _ = self.handle_create(id).await;
+            }
+        }
+
+        info!(
+            files = seen.len(),
+            notes = self.note_files.len(),
+            duration_ms = start.elapsed().as_millis(),
+            "initial build finished"
+        );
+        let _ = self.notes_service.set_build_finished().await;
+
+        Ok(())
+    }
+
+    /// Finds every source file, along with its virtual path.
+    async fn scan_sources(&self) -> Vec<(PathBuf, VirtualPath)> {
         let roots = self.source_directories.clone();
         let project_directory = self.project_directory.clone();
-        let paths = tokio::task::spawn_blocking(move || {
+        let sources = self.sources.clone();
+        let files = self.files.clone();
+
+        tokio::task::spawn_blocking(move || {
             roots
                 .into_iter()
-                .flat_map(|root| WalkDir::new(root).into_iter())
-                .filter_map(|result| {
-                    result
-                        .map(DirEntry::into_path)
-                        .ok()
-                        .filter(|path| path.extension().is_some_and(|s| s == "typ"))
+                .flat_map(|root| {
+                    files.walk(&root, &|directory| !sources.is_ignored(directory, true))
                 })
+                .filter(|path| sources.is_source(path))
                 .filter_map(|path| {
                     VirtualPath::within_root(&path, &project_directory)
                         .map(|virtual_path| (path, virtual_path))
@@ -152,23 +310,16 @@ impl BuildService {
                 .collect::<Vec<_>>()
         })
         .await
-        .unwrap();
-
-        let mut seen = HashSet::new();
+        .unwrap()
+    }
 
-        for (path, virtual_path) in paths {
-            if seen.insert(path.clone()) {
-                let id = FileId::new(None, virtual_path);
+    pub async fn start(&mut self) -> Result<(), Box<dyn Error>> {
+        self.build_all().await?;
 
-                let _ = self.handle_create(id).await;
-            }
+        if let Some(watcher) = &mut self.watcher {
+            watcher.watch(&self.project_directory, notify::RecursiveMode::Recursive)?;
         }
 
-        let _ = self.notes_service.set_build_finished().await;
-
-        self.watcher
-            .watch(&self.project_directory, notify::RecursiveMode::Recursive)?;
-
         Ok(())
     }
 
@@ -179,7 +330,7 @@ impl BuildService {
         tokio::select! {
             _ = self.start() => (),
             _ = cancel.cancelled() => {
-                println!("Build server cancelled");
+                info!("build service cancelled");
                 self.receiver.close();
 
                 return
@@ -187,58 +338,43 @@ impl BuildService {
         };
 
         loop {
+            // Events which arrived during a rebuild but couldn't be merged
+            // into it
+            if !self.deferred.is_empty() {
+                let changes = mem::take(&mut self.deferred);
+                self.handle_changes(changes).await;
+
+                continue;
+            }
+            if let Some(reconfiguration) = self.reconfiguration.take() {
+                self.reconfigure(reconfiguration).await;
+
+                continue;
+            }
+            if mem::take(&mut self.rescan) {
+                self.rescan_sources().await;
+
+                continue;
+            }
+
             tokio::select! {
-                option = self.receiver.recv() => if let Some(result) = option {
-                    if let Ok(events) = result {
-
-                        for event in events {
-                            use notify::EventKind;
-
-                            match event.kind {
-                                EventKind::Access(_) | EventKind::Any | EventKind::Other => (),
-                                EventKind::Create(_) => {
-                                    assert_eq!(event.paths.len(), 1);
-
-                                    let path = &event.paths[0];
-                                    let virtual_path = VirtualPath::within_root(path, &self.project_directory).unwrap();
-                                    let id = FileId::new(None, virtual_path);
-
-                                    if id.package().is_none()
-                                        && self.is_source_typ_file(path) {
-                                        self.handle_create(id).await;
-                                    }
-                                },
-                                EventKind::Modify(_) => {
-                                    assert_eq!(event.paths.len(), 1); // TODO
-
-                                    let path = &event.paths[0];
-                                    let virtual_path = VirtualPath::within_root(path, &self.project_directory).unwrap();
-                                    let id = FileId::new(None, virtual_path);
-                                    let is_source = id.package().is_none()
-                                        && self.is_source_typ_file(path);
-
-                                    if self.graph.contains_node(id) || is_source {
-                                        self.handle_modify(id).await;
-                                    }
-                                },
-                                EventKind::Remove(_) => {
-                                    assert_eq!(event.paths.len(), 1);
-
-                                    let path = &event.paths[0];
-                                    let virtual_path = VirtualPath::within_root(path, &self.project_directory).unwrap();
-                                    let id = FileId::new(None, virtual_path);
-
-                                    self.handle_remove(id).await;
-                                },
-                            }
-                        }
+                option = self.receiver.recv() => match option {
+                    Some(Ok(events)) => {
+                        let changes = self.file_changes(events);
+                        self.handle_changes(changes).await;
+                    }
+                    Some(Err(_)) => (),
+                    None => break,
+                },
+                Some(message) = self.messages.recv() => {
+                    if let Some(id) = self.handle_message(message).await {
+                        self.handle_modify(HashSet::from([id]), HashSet::new()).await;
                     }
-                } else {
-                    break
                 },
                 _ = cancel.cancelled() => {
-                    println!("Build server cancelled");
+                    info!("build service cancelled");
                     self.receiver.close();
+                    self.messages.close();
 
                     break
                 }
@@ -246,128 +382,773 @@ impl BuildService {
         }
     }
 
-    async fn handle_create(&mut self, i: FileId) {
-        match build(
-            self.resources.clone(),
-            self.package_storage.clone(),
-            self.slots.clone(),
-            self.build_subdirectory.clone(),
-            i,
-        )
-        .await
-        {
-            Ok(Ok((warnings, outputs, dependencies))) => {
-                self.graph.add_node(i);
-                for j in dependencies {
-                    self.graph.add_edge(i, j, ());
+    /// Turns a debounced batch of file events into changes to single files,
+    /// leaving out ignored paths.
+    fn file_changes(&mut self, events: Vec<DebouncedEvent>) -> Vec<FileChange> {
+        use notify::{EventKind, event::ModifyKind};
+
+        let mut changes = Vec::new();
+
+        for event in events {
+            if self.is_dropped(&event) {
+                continue;
+            }
+
+            let kind = event.kind;
+            for path in event.event.paths {
+                let change = match kind {
+                    EventKind::Access(_) | EventKind::Any | EventKind::Other => continue,
+                    EventKind::Create(_) => FileChange::Created(path),
+                    // Atomic saves replace a file by renaming another one over
+                    // it, so what matters is whether the path exists now
+                    EventKind::Remove(_) if self.files.exists(&path) => FileChange::Modified(path),
+                    EventKind::Modify(ModifyKind::Name(_)) if self.files.exists(&path) => {
+                        FileChange::Created(path)
+                    }
+                    EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_)) => {
+                        FileChange::Removed(path)
+                    }
+                    EventKind::Modify(_) => FileChange::Modified(path),
+                };
+
+                changes.push(change);
+            }
+        }
+
+        changes
+    }
+
+    /// Handles the changes from a debounced batch of file events. Modified
+    /// files from the whole batch are rebuilt together, so a burst of changes
+    /// builds each affected file once.
+    async fn handle_changes(&mut self, changes: Vec<FileChange>) {
+        let mut modified = HashSet::new();
+        // Source files which notes read before they existed
+        let mut created = HashSet::new();
+        // Files which were created or removed in this batch already reflect
+        // any later modifications
+        let mut handled = HashSet::new();
+
+        for change in changes {
+            match change {
+                FileChange::Created(path) => {
+                    let id = self.file_id(&path);
+
+                    if self.dependencies.contains(id) {
+                        // Either replaced by an atomic save, or a file which was
+                        // missing when something tried to read it
+                        if !handled.contains(&id) {
+                            modified.insert(id);
+                        }
+                        if self.is_new_source(&path, id) {
+                            created.insert(id);
+                        }
+                    } else if id.package().is_none() && self.sources.is_source(&path) {
+                        handled.insert(id);
+                        modified.remove(&id);
+                        self.handle_create(id).await;
+                    }
+                }
+                FileChange::Modified(path) => {
+                    let id = self.file_id(&path);
+
+                    if !handled.contains(&id) && self.is_tracked(&path, id) {
+                        modified.insert(id);
+                    }
+                }
+                FileChange::Removed(path) => {
+                    let id = self.file_id(&path);
+
+                    if self.dependencies.is_main(id) {
+                        handled.insert(id);
+                        modified.remove(&id);
+                        self.handle_remove(id).await;
+                    }
+                    // Files which imported or read it need to find out it's gone
+                    if self.dependencies.contains(id) {
+                        modified.insert(id);
+                    }
                 }
+            }
+        }
+
+        if !modified.is_empty() || !created.is_empty() {
+            self.handle_modify(modified, created).await;
+        }
+    }
+
+    fn file_id(&self, path: &Path) -> FileId {
+        let virtual_path = VirtualPath::within_root(path, &self.project_directory).unwrap();
+
+        FileId::new(None, virtual_path)
+    }
+
+    /// Handles a message from the editor or HTTP services. Returns the file
+    /// that needs to be rebuilt, if any.
+    async fn handle_message(&mut self, message: BuildMessage) -> Option<FileId> {
+        match message {
+            BuildMessage::DidChange(path, changes, sender) => {
+                let result = self.did_change(&path, changes).await;
+                let id = result.as_ref().ok().copied();
+                let _ = sender.send(result.map(|_| ()));
+
+                id.filter(|&id| self.is_tracked(&path, id))
+            }
+            BuildMessage::DependencyGraph(sender) => {
+                let _ = sender.send(self.dependency_graph());
+
+                None
+            }
+            BuildMessage::Reconfigure(reconfiguration) => {
+                // Applied between rebuilds, since it can touch every file
+                self.reconfiguration = Some(match self.reconfiguration.take() {
+                    Some(previous) => previous.merge(*reconfiguration),
+                    None => *reconfiguration,
+                });
+
+                None
+            }
+            BuildMessage::DidClose(path, sender) => {
+                let Some(virtual_path) = VirtualPath::within_root(&path, &self.project_directory)
+                else {
+                    let _ = sender.send(Err(OverlayError::PathOutsideRoot));
+
+                    return None;
+                };
+                let id = FileId::new(None, virtual_path);
+                let removed = self.overlay.remove(id);
+                let _ = sender.send(Ok(()));
+
+                // The buffer might have been closed without saving, so go
+                // back to what's on disk.
+                (removed && self.is_tracked(&path, id)).then_some(id)
+            }
+        }
+    }
+
+    /// Applies settings from a reloaded config. Source files which are no
+    /// longer notes are removed and new ones are built, and everything is
+    /// rebuilt if the notes would come out differently.
+    async fn reconfigure(
+        &mut self,
+        Reconfiguration {
+            sources,
+            note_config,
+            resources,
+        }: Reconfiguration,
+    ) {
+        let rebuild_all = note_config.is_some() || resources.is_some();
+        if let Some(note_config) = note_config {
+            self.note_config = note_config;
+        }
+        if let Some(resources) = resources {
+            self.resources = Arc::new(resources);
+        }
+
+        let mut created = HashSet::new();
+        if let Some((source_directories, globs)) = sources {
+            let files = self.files.clone();
+            let project_directory = self.project_directory.clone();
+            let roots = source_directories.clone();
+            let build_subdirectory = self.build_subdirectory.as_ref().clone();
+            self.sources = tokio::task::spawn_blocking(move || {
+                SourceFilter::new(
+                    files.as_ref(),
+                    project_directory,
+                    roots,
+                    build_subdirectory,
+                    globs,
+                )
+            })
+            .await
+            .unwrap();
+            self.source_directories = source_directories;
+
+            created = self.rescan_sources().await;
+        }
+
+        if rebuild_all {
+            let files = self
+                .dependencies
+                .mains()
+                .filter(|id| !created.contains(id))
+                .collect();
+            self.rebuild(files).await;
+        }
+    }
+
+    /// Removes the notes of files which aren't sources anymore and builds the
+    /// new sources. Returns the files which were built.
+    async fn rescan_sources(&mut self) -> HashSet<FileId> {
+        let ids: HashSet<FileId> = self
+            .scan_sources()
+            .await
+            .into_iter()
+            .map(|(_, virtual_path)| FileId::new(None, virtual_path))
+            .collect();
+        let removed: Vec<FileId> = self
+            .dependencies
+            .mains()
+            .filter(|id| !ids.contains(id))
+            .collect();
+
+        let mut created = HashSet::new();
+        for id in removed {
+            self.handle_remove(id).await;
+        }
+        for id in ids {
+            if !self.dependencies.is_main(id) {
+                created.insert(id);
+                self.handle_create(id).await;
+            }
+        }
+        info!(
+            created = created.len(),
+            notes = self.note_files.len(),
+            "rescanned source directories"
+        );
+
+        created
+    }
+
+    pub fn dependency_graph(&self) -> FileGraph {
+        self.dependencies.export(file_name)
+    }
+
+    async fn did_change(
+        &mut self,
+        path: &Path,
+        changes: Vec<TextChange>,
+    ) -> Result<FileId, OverlayError> {
+        let virtual_path = VirtualPath::within_root(path, &self.project_directory)
+            .ok_or(OverlayError::PathOutsideRoot)?;
+        let id = FileId::new(None, virtual_path);
+
+        let base = if !self.overlay.contains(id)
+            && changes.first().is_some_and(|change| change.range.is_some())
+        {
+            let files = self.files.clone();
+            let path = path.to_owned();
+            let contents = tokio::task::spawn_blocking(move || files.read(&path))
+                .await
+                .unwrap()
+                .and_then(|bytes| {
+                    String::from_utf8(bytes)
+                        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+                })
+                .map_err(OverlayError::Read)?;
+
+            Some(contents)
+        } else {
+            None
+        };
+
+        self.overlay.edit(id, base, changes)?;
+
+        Ok(id)
+    }
+
+    fn context(&self) -> BuildContext<S> {
+        BuildContext {
+            resources: self.resources.clone(),
+            files: self.files.clone(),
+            package_storage: self.package_storage.clone(),
+            slots: self.slots.clone(),
+            overlay: self.overlay.clone(),
+            note_config: self.note_config.clone(),
+            fragments: self.fragments.clone(),
+            build_subdirectory: self.build_subdirectory.clone(),
+            metrics: self.metrics.clone(),
+            stale: CancellationToken::new(),
+        }
+    }
+
+    async fn handle_create(&mut self, i: FileId) {
+        // A file might have been read from the same path before it was removed
+        if let Some(slot) = self.slots.lock().get_mut(&i) {
+            slot.reset();
+        }
+
+        let (result, dependencies) = build(self.context(), i).await;
+        self.dependencies.set_dependencies(i, dependencies);
+
+        match result {
+            Ok((warnings, outputs)) => {
+                self.record_writes(&outputs);
+                let transcluding = self.record_notes(i, &outputs);
 
                 let _ = self
                     .notes_service
                     .update_notes(vec![(i, Ok((warnings, outputs)))])
                     .await;
+
+                if !transcluding.is_empty() {
+                    self.rebuild(transcluding.into_iter().collect()).await;
+                }
             }
-            Ok(Err(errors)) => {
+            Err(errors) => {
                 let _ = self
                     .notes_service
                     .update_notes(vec![(i, Err(errors))])
                     .await;
             }
-            Err(error) => {
-                // Here we failed to write on of the fragments to the build
-                // directory. This should result in a fatal error, so we need to
-                // tell the rest of the application to shutdown.
-
-                println!("Failed to write fragment to build directory: {}", error);
-                self.cancel.cancel();
-            }
         }
     }
 
-    async fn handle_modify(&mut self, i: FileId) {
-        // TODO: Next we need to debug creates and updates until we get the
-        // behavior we're expecting all the way through. Then we can work on the
-        // UI in earnest.
-        let mut bfs = Bfs::new(&self.graph, i);
-        let mut dependents = Vec::new();
+    /// Rebuilds everything depending on the modified files, along with the
+    /// created files, which are compiled for the first time.
+    async fn handle_modify(&mut self, files: HashSet<FileId>, created: HashSet<FileId>) {
+        let mut dependents = created;
 
-        {
-            dependents.push(i);
+        for i in files {
+            self.invalidate(i, &mut dependents);
+        }
 
-            let mut slots = self.slots.lock();
+        self.rebuild(dependents).await;
+    }
 
-            // Note: BFS starts by traversing i, so we don't need to do that manually
-            while let Some(j) = bfs.next(&self.graph) {
-                if self.is_source.contains(&j) {
-                    dependents.push(j);
-                }
-                slots.get_mut(&j).unwrap().reset();
+    /// Forgets the cached contents of a file and everything depending on it,
+    /// and collects the main files which need to be rebuilt because of it.
+    fn invalidate(&self, i: FileId, dependents: &mut HashSet<FileId>) {
+        let mut slots = self.slots.lock();
+
+        for j in self.dependencies.affected(i) {
+            // A source file nothing knows about yet is built for the first time
+            if self.dependencies.is_main(j) || !self.dependencies.contains(j) {
+                dependents.insert(j);
+            }
+            if let Some(slot) = slots.get_mut(&j) {
+                slot.reset();
             }
         }
+    }
 
-        let mut results = Vec::with_capacity(dependents.len());
+    /// Rebuilds the given files and any files that transclude notes from them,
+    /// each after the files it transcludes from. Changes arriving in the
+    /// meantime are merged into the same rebuild, and a compile whose inputs
+    /// changed while it was running is abandoned and started over.
+    async fn rebuild(&mut self, files: HashSet<FileId>) {
+        let start = Instant::now();
+        let cancel = self.cancel.clone();
+        let mut pending = files;
+        let mut rebuilt = HashSet::new();
+        let mut results = Vec::with_capacity(pending.len());
+        let mut compiles = 0;
+
+        self.add_transcluding_files(&mut pending);
+
+        while let Some(j) = self.next_file(&pending) {
+            pending.remove(&j);
+            rebuilt.insert(j);
+            compiles += 1;
+
+            let context = self.context();
+            let stale = context.stale.clone();
+            let build = build(context, j);
+            tokio::pin!(build);
+
+            let result = loop {
+                let mut invalidated = HashSet::new();
+
+                tokio::select! {
+                    result = &mut build => break Some(result),
+                    Some(result) = self.receiver.recv() => if let Ok(events) = result {
+                        self.merge_events(events, &mut invalidated);
+                    },
+                    Some(message) = self.messages.recv() => {
+                        if let Some(id) = self.handle_message(message).await {
+                            self.invalidate(id, &mut invalidated);
+                        }
+                    },
+                    _ = cancel.cancelled() => {
+                        stale.cancel();
 
-        for j in dependents {
-            let result = build(
-                self.resources.clone(),
-                self.package_storage.clone(),
-                self.slots.clone(),
-                self.build_subdirectory.clone(),
-                j,
-            )
-            .await;
+                        return;
+                    }
+                }
 
-            match result {
-                Ok(Ok((warnings, outputs, dependencies))) => {
-                    let ks: Vec<FileId> = self
-                        .graph
-                        .neighbors_directed(j, Direction::Incoming)
-                        .collect();
-
-                    for k in ks {
-                        self.graph.remove_edge(k, j);
+                if !invalidated.is_empty() {
+                    for k in &invalidated {
+                        rebuilt.remove(k);
                     }
-                    for k in dependencies {
-                        if k.package().is_none() {
-                            self.graph.add_edge(k, j, ());
+                    pending.extend(invalidated);
+                    self.add_transcluding_files(&mut pending);
+
+                    if pending.contains(&j) {
+                        debug!(file = %file_name(j), "abandoning stale compile");
+                        stale.cancel();
+
+                        break None;
+                    }
+                }
+            };
+
+            let Some((result, dependencies)) = result else {
+                continue;
+            };
+            self.dependencies.set_dependencies(j, dependencies);
+
+            match result {
+                Ok((warnings, outputs)) => {
+                    self.record_writes(&outputs);
+                    // New transclusions only show up after compiling. Every
+                    // file is still built at most once for them, in case they
+                    // form a cycle.
+                    for k in self.record_notes(j, &outputs) {
+                        if !rebuilt.contains(&k) {
+                            pending.insert(k);
                         }
                     }
 
                     results.push((j, Ok((warnings, outputs))));
                 }
-                Ok(Err(error)) => results.push((j, Err(error))),
-                Err(error) => {
-                    // We failed to save the fragment to the build directory, we
-                    // need to tell the rest of application to shutdown
+                Err(error) => results.push((j, Err(error))),
+            }
+        }
+
+        self.metrics.observe_rebuild(rebuilt.len());
+        info!(
+            files = rebuilt.len(),
+            compiles,
+            duration_ms = start.elapsed().as_millis(),
+            "rebuild finished"
+        );
+        let _ = self.notes_service.update_notes(results).await;
+    }
 
-                    println!("Failed to save fragment to build directory: {}", error);
-                    self.cancel.cancel();
+    /// Merges file modifications which arrive during a rebuild into it. Created
+    /// and removed files are handled once the rebuild is done.
+    fn merge_events(&mut self, events: Vec<DebouncedEvent>, invalidated: &mut HashSet<FileId>) {
+        for change in self.file_changes(events) {
+            match &change {
+                FileChange::Modified(path) | FileChange::Created(path) => {
+                    let id = self.file_id(path);
+
+                    if self.dependencies.contains(id)
+                        || (change.is_modified() && self.is_tracked(path, id))
+                    {
+                        if !change.is_modified() && self.is_new_source(path, id) {
+                            invalidated.insert(id);
+                        }
+                        self.invalidate(id, invalidated);
+                    } else {
+                        self.deferred.push(change);
+                    }
                 }
+                FileChange::Removed(_) => self.deferred.push(change),
             }
         }
+    }
 
-        let _ = self.notes_service.update_notes(results).await;
+    /// Adds the files transcluding notes from any pending file, repeatedly.
+    fn add_transcluding_files(&self, pending: &mut HashSet<FileId>) {
+        let mut stack: Vec<FileId> = pending.iter().copied().collect();
+
+        while let Some(j) = stack.pop() {
+            let ids = self.notes.get(&j).into_iter().flatten();
+
+            for k in self.files_transcluding(j, ids) {
+                if pending.insert(k) {
+                    stack.push(k);
+                }
+            }
+        }
+    }
+
+    /// The pending file to build next. Files come after the files whose notes
+    /// they transclude, so that transclusions are expanded from up to date
+    /// fragments.
+    fn next_file(&self, pending: &HashSet<FileId>) -> Option<FileId> {
+        let mut order: DiGraphMap<FileId, ()> = DiGraphMap::new();
+
+        for &j in pending {
+            order.add_node(j);
+
+            let ids = self.notes.get(&j).into_iter().flatten();
+            for k in self.files_transcluding(j, ids) {
+                if pending.contains(&k) {
+                    order.add_edge(j, k, ());
+                }
+            }
+        }
+
+        // Components come out in reverse topological order. Files in a cycle
+        // can go in any order.
+        tarjan_scc(&order)
+            .pop()
+            .and_then(|component| component.into_iter().min_by_key(|id| id.into_raw()))
+    }
+
+    /// Keeps track of builds in which none of the notes could be written to
+    /// the build directory. Individual failures are reported on the notes
+    /// themselves, but if writes keep failing the build directory is most
+    /// likely gone for good and the application is stopped.
+    fn record_writes(&mut self, outputs: &[NoteData]) {
+        if outputs.is_empty() {
+            return;
+        }
+
+        if outputs.iter().all(|output| output.error.is_some()) {
+            self.failed_builds += 1;
+
+            if self.failed_builds >= MAX_CONSECUTIVE_FAILURES {
+                error!(
+                    builds = self.failed_builds,
+                    directory = %self.build_subdirectory.display(),
+                    "repeatedly failed to write to the build directory, shutting down"
+                );
+                self.cancel.cancel();
+            }
+        } else {
+            self.failed_builds = 0;
+        }
     }
 
     async fn handle_remove(&mut self, i: FileId) {
-        self.graph.remove_node(i);
-        self.is_source.remove(&i);
+        self.dependencies.remove(i);
+        // Otherwise a file created at the same path would start out with the
+        // old contents
+        self.slots.lock().remove(&i);
+        let transcluding = self.record_notes(i, &[]);
+        self.notes.remove(&i);
 
         // Note, notes service handles clean up of fragment files in build
         // directory.
         let _ = self.notes_service.remove_notes(i).await;
+
+        if !transcluding.is_empty() {
+            self.rebuild(transcluding.into_iter().collect()).await;
+        }
     }
 
-    fn is_source_typ_file(&self, path: &Path) -> bool {
-        path.extension().is_some_and(|e| e == "typ")
-            && self
-                .source_directories
-                .iter()
-                .any(|directory| path.starts_with(directory))
+    /// Records which notes a file produced and what they transclude. Returns
+    /// the other files that transclude any of the file's old or new notes and
+    /// so need to be rebuilt.
+    fn record_notes(&mut self, i: FileId, outputs: &[NoteData]) -> Vec<FileId> {
+        let previous = self
+            .notes
+            .insert(i, outputs.iter().map(|data| data.id).collect())
+            .unwrap_or_default();
+
+        for &id in &previous {
+            if self.note_files.get(&id) == Some(&i) && !outputs.iter().any(|data| data.id == id) {
+                self.note_files.remove(&id);
+                self.fragments.lock().remove(&id);
+
+                let ks: Vec<Uuid> = self
+                    .transclusions
+                    .neighbors_directed(id, Direction::Incoming)
+                    .collect();
+                for k in ks {
+                    self.transclusions.remove_edge(k, id);
+                }
+            }
+        }
+
+        for data in outputs {
+            self.note_files.insert(data.id, i);
+            self.transclusions.add_node(data.id);
+
+            let ks: Vec<Uuid> = self
+                .transclusions
+                .neighbors_directed(data.id, Direction::Incoming)
+                .collect();
+            for k in ks {
+                self.transclusions.remove_edge(k, data.id);
+            }
+            for &k in &data.transclusions {
+                self.transclusions.add_edge(k, data.id, ());
+            }
+        }
+
+        self.files_transcluding(
+            i,
+            previous.iter().chain(outputs.iter().map(|data| &data.id)),
+        )
+    }
+
+    /// Files other than `i` with notes that transclude any of the given notes.
+    fn files_transcluding<'a>(
+        &self,
+        i: FileId,
+        ids: impl IntoIterator<Item = &'a Uuid>,
+    ) -> Vec<FileId> {
+        let mut files: Vec<FileId> = ids
+            .into_iter()
+            .flat_map(|&id| {
+                self.transclusions
+                    .neighbors_directed(id, Direction::Outgoing)
+            })
+            .filter_map(|id| self.note_files.get(&id).copied())
+            .filter(|&j| j != i)
+            .collect();
+        files.sort_by_key(|id| id.into_raw());
+        files.dedup();
+
+        files
+    }
+
+    /// Whether a change to the file should trigger a rebuild.
+    fn is_tracked(&self, path: &Path, id: FileId) -> bool {
+        self.dependencies.contains(id) || (id.package().is_none() && self.sources.is_source(path))
+    }
+
+    /// Whether a created file is a source which isn't compiled on its own yet,
+    /// because notes only read it while it was missing.
+    fn is_new_source(&self, path: &Path, id: FileId) -> bool {
+        id.package().is_none() && !self.dependencies.is_main(id) && self.sources.is_source(path)
+    }
+
+    /// Whether the event only concerns ignored paths. Ignored files which
+    /// notes depend on still count, but the build directory and `.git` never
+    /// do. Changes to ignore files reload the rules, and the sources are
+    /// rescanned once the current changes are handled.
+    fn is_dropped(&mut self, event: &DebouncedEvent) -> bool {
+        let mut dropped = true;
+
+        for path in &event.paths {
+            if self.sources.reload(self.files.as_ref(), path) {
+                self.rescan = true;
+
+                continue;
+            }
+
+            let depended_on = VirtualPath::within_root(path, &self.project_directory).is_some_and(
+                |virtual_path| self.dependencies.contains(FileId::new(None, virtual_path)),
+            );
+            if !self.sources.is_ignored(path, path.is_dir()) || depended_on {
+                dropped = false;
+            }
+        }
+
+        dropped
+    }
+}
+
+/// What happened to a single file, with renames split into a removal and a
+/// creation.
+enum FileChange {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+}
+
+impl FileChange {
+    fn is_modified(&self) -> bool {
+        matches!(self, Self::Modified(_))
+    }
+}
+
+enum BuildMessage {
+    DidChange(
+        PathBuf,
+        Vec<TextChange>,
+        oneshot::Sender<Result<(), OverlayError>>,
+    ),
+    DidClose(PathBuf, oneshot::Sender<Result<(), OverlayError>>),
+    DependencyGraph(oneshot::Sender<FileGraph>),
+    Reconfigure(Box<Reconfiguration>),
+}
+
+/// Settings from a reloaded config which the build service applies while
+/// running. Unchanged settings are left out.
+#[derive(Debug, Default)]
+pub struct Reconfiguration {
+    pub sources: Option<(Vec<PathBuf>, SourceGlobs)>,
+    pub note_config: Option<NoteConfig>,
+    pub resources: Option<Resources>,
+}
+
+impl Reconfiguration {
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_none() && self.note_config.is_none() && self.resources.is_none()
+    }
+
+    /// Combines with a later reconfiguration, whose settings win.
+    fn merge(self, later: Self) -> Self {
+        Self {
+            sources: later.sources.or(self.sources),
+            note_config: later.note_config.or(self.note_config),
+            resources: later.resources.or(self.resources),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BuildServiceHandle {
+    sender: mpsc::Sender<BuildMessage>,
+}
+
+#[derive(Debug, Error)]
+pub enum BuildServiceHandleError {
+    #[error("send error")]
+    Send,
+    #[error("receive error")]
+    Receive,
+}
+
+impl BuildServiceHandle {
+    /// Applies changes to the unsaved contents of an editor buffer and
+    /// rebuilds anything that depends on it.
+    pub async fn did_change(
+        &self,
+        path: PathBuf,
+        changes: Vec<TextChange>,
+    ) -> Result<Result<(), OverlayError>, BuildServiceHandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(BuildMessage::DidChange(path, changes, sender))
+            .await
+            .map_err(|_| BuildServiceHandleError::Send)?;
+
+        receiver.await.map_err(|_| BuildServiceHandleError::Receive)
+    }
+
+    /// Applies settings from a reloaded config once the current rebuild is
+    /// done.
+    pub async fn reconfigure(
+        &self,
+        reconfiguration: Reconfiguration,
+    ) -> Result<(), BuildServiceHandleError> {
+        self.sender
+            .send(BuildMessage::Reconfigure(Box::new(reconfiguration)))
+            .await
+            .map_err(|_| BuildServiceHandleError::Send)
+    }
+
+    /// The number of messages waiting to be handled.
+    pub fn queue_depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    pub async fn dependency_graph(&self) -> Result<FileGraph, BuildServiceHandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(BuildMessage::DependencyGraph(sender))
+            .await
+            .map_err(|_| BuildServiceHandleError::Send)?;
+
+        receiver.await.map_err(|_| BuildServiceHandleError::Receive)
+    }
+
+    /// Drops the unsaved contents of an editor buffer so that the file is
+    /// read from disk again.
+    pub async fn did_close(
+        &self,
+        path: PathBuf,
+    ) -> Result<Result<(), OverlayError>, BuildServiceHandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(BuildMessage::DidClose(path, sender))
+            .await
+            .map_err(|_| BuildServiceHandleError::Send)?;
+
+        receiver.await.map_err(|_| BuildServiceHandleError::Receive)
     }
 }
 
-type CompileOutput = (Html, HtmlDocument, HashSet<FileId>);
+type CompileOutput = (Html, HtmlDocument);
+type CompileResult = Result<(Vec<String>, CompileOutput), Vec<String>>;
 
 fn into_messages(errors: EcoVec<SourceDiagnostic>) -> Vec<String> {
     errors
@@ -378,10 +1159,13 @@ fn into_messages(errors: EcoVec<SourceDiagnostic>) -> Vec<String> {
 
 fn compile<S>(
     resources: Arc<Resources>,
+    files: Arc<dyn FileSource>,
     package_storage: PackageStorage<S>,
     slots: Arc<Mutex<HashMap<FileId, FileSlot>>>,
+    overlay: Overlay,
+    stale: CancellationToken,
     main_id: FileId,
-) -> Result<(Vec<String>, CompileOutput), Vec<String>>
+) -> (CompileResult, HashSet<FileId>)
 where
     S: Send + Sync,
     S: PackageService,
@@ -389,20 +1173,32 @@ where
     PackageError: From<S::GetPackageServiceError>,
     S::GetPackageBuffer: Buf,
 {
-    let world = SystemWorld::new(resources, package_storage, slots, main_id);
+    let world = SystemWorld::new(
+        resources,
+        files,
+        package_storage,
+        slots,
+        overlay,
+        stale,
+        main_id,
+    );
 
     let Warned {
         output: result,
         warnings,
     } = typst::compile::<HtmlDocument>(&world);
-    let document = result.map_err(into_messages)?;
-
-    let output = typst_html::html(&document).map_err(into_messages)?;
-    let html = Html::parse_document(&output);
-
-    let warnings = into_messages(warnings);
-
-    Ok((warnings, (html, document, world.into_dependencies())))
+    let result = result.and_then(|document| {
+        let output = typst_html::html(&document)?;
+
+        Ok((
+            into_messages(warnings),
+            (Html::parse_document(&output), document),
+        ))
+    });
+
+    // Files read by a failed compile are kept too, so that fixing a missing
+    // file triggers a rebuild
+    (result.map_err(into_messages), world.into_dependencies())
 }
 
 pub struct NoteUuid(pub Uuid);
@@ -426,7 +1222,9 @@ impl FromStr for NoteUuid {
     }
 }
 
-pub struct NoteLink(pub Uuid);
+/// A link to a note, optionally pointing at an anchor within it, written as
+/// `note://uuid` or `note://uuid#anchor`.
+pub struct NoteLink(pub Uuid, pub Option<String>);
 
 pub enum NoteLinkParseError {
     MissingPrefix,
@@ -440,13 +1238,39 @@ impl FromStr for NoteLink {
         s.strip_prefix("note://")
             .ok_or(NoteLinkParseError::MissingPrefix)
             .and_then(|s| {
-                Uuid::from_str(s)
-                    .map(NoteLink)
+                let (uuid, anchor) = match s.split_once('#') {
+                    Some((uuid, anchor)) => (uuid, Some(anchor.into())),
+                    None => (s, None),
+                };
+
+                Uuid::from_str(uuid)
+                    .map(|uuid| NoteLink(uuid, anchor))
                     .map_err(NoteLinkParseError::Uuid)
             })
     }
 }
 
+pub struct TranscludeLink(pub Uuid);
+
+pub enum TranscludeLinkParseError {
+    MissingPrefix,
+    Uuid(uuid::Error),
+}
+
+impl FromStr for TranscludeLink {
+    type Err = TranscludeLinkParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_prefix("transclude://")
+            .ok_or(TranscludeLinkParseError::MissingPrefix)
+            .and_then(|s| {
+                Uuid::from_str(s)
+                    .map(TranscludeLink)
+                    .map_err(TranscludeLinkParseError::Uuid)
+            })
+    }
+}
+
 fn clone_subtree<T: Clone>(source: NodeRef<T>) -> Tree<T> {
     let mut tree = Tree::new(source.value().clone());
     let mut queue = std::collections::VecDeque::new();
@@ -464,63 +1288,541 @@ fn clone_subtree<T: Clone>(source: NodeRef<T>) -> Tree<T> {
     tree
 }
 
-fn extract_note_fragments(html: &Html, document: &HtmlDocument) -> Vec<(String, Uuid, Html)> {
+/// A heading in the HTML output together with what the introspector knows
+/// about the heading element it was generated from.
+struct HeadingNode {
+    node: NodeId,
+    label: Option<Label>,
+    level: usize,
+    title: String,
+    /// The value of a `#metadata(..) <note-meta>` following the heading.
+    metadata: Option<Value>,
+}
+
+fn html_heading_level(element: ElementRef) -> Option<usize> {
+    match element.value().name() {
+        "h2" => Some(1),
+        "h3" => Some(2),
+        "h4" => Some(3),
+        "h5" => Some(4),
+        "h6" => Some(5),
+        _ if element.attr("role") == Some("heading") => element
+            .attr("aria-level")
+            .and_then(|level| level.parse::<usize>().ok())
+            .and_then(|level| level.checked_sub(1)),
+        _ => None,
+    }
+}
+
+/// Pairs every heading in the HTML output with the heading element it was
+/// generated from. Both appear in document order, so they are matched by
+/// position, and the levels have to agree for the matching to be trusted.
+fn match_headings(html: &Html, document: &HtmlDocument) -> Result<Vec<HeadingNode>, String> {
     let selector =
         typst::foundations::Selector::Elem(typst::foundations::Element::of::<HeadingElem>(), None);
-    let matches: HashMap<Uuid, String> = document
-        .introspector()
-        .query(&selector)
-        .into_iter()
-        .filter_map(|c| {
-            let NoteUuid(uuid) = c.label().and_then(|l| {
-                let t = l.resolve();
+    let elements = document.introspector().query(&selector);
+
+    let header_selector = Selector::parse(r#"h2, h3, h4, h5, h6, [role="heading"]"#).unwrap();
+    let headers: Vec<_> = html.select(&header_selector).collect();
 
-                t.as_str().parse().ok()
-            })?;
-            let text = c.plain_text();
-            let stripped = text.strip_prefix("Section").unwrap_or(&text);
+    if elements.len() != headers.len() {
+        return Err(format!(
+            "couldn't split notes: found {} headings in the document but {} in the HTML output",
+            elements.len(),
+            headers.len()
+        ));
+    }
+
+    elements
+        .iter()
+        .zip(headers)
+        .map(|(content, header)| {
+            let level = content
+                .to_packed::<HeadingElem>()
+                .unwrap()
+                .resolve_level(StyleChain::default())
+                .get();
+            let title = header.text().collect::<String>().trim().to_string();
+
+            if html_heading_level(header) != Some(level) {
+                return Err(format!(
+                    "couldn't split notes: heading \"{title}\" doesn't match its HTML output"
+                ));
+            }
 
-            Some((uuid, stripped.into()))
+            Ok(HeadingNode {
+                node: header.id(),
+                label: content.label(),
+                level,
+                title,
+                metadata: None,
+            })
         })
+        .collect()
+}
+
+/// Assigns each `#metadata(..) <note-meta>` to the heading before it in the
+/// document.
+fn assign_note_metadata(
+    document: &HtmlDocument,
+    headings: &mut [HeadingNode],
+    warnings: &mut Vec<String>,
+) {
+    let label = Label::new(PicoStr::intern("note-meta")).unwrap();
+    let selector = typst::foundations::Selector::Or(EcoVec::from([
+        typst::foundations::Selector::Elem(typst::foundations::Element::of::<HeadingElem>(), None),
+        typst::foundations::Selector::Label(label),
+    ]));
+    let elements = document.introspector().query(&selector);
+
+    let mut current: Option<usize> = None;
+    for content in elements.iter() {
+        if content.is::<HeadingElem>() {
+            current = Some(current.map_or(0, |i| i + 1));
+
+            continue;
+        }
+
+        let Some(metadata) = content.to_packed::<MetadataElem>() else {
+            continue;
+        };
+
+        match current.and_then(|i| headings.get_mut(i)) {
+            Some(heading) if heading.metadata.is_none() => {
+                heading.metadata = Some(metadata.value.clone());
+            }
+            Some(heading) => warnings.push(format!(
+                "heading \"{}\" has more than one note-meta metadata, ignoring all but the first",
+                heading.title
+            )),
+            None => warnings.push("note-meta metadata must follow a note heading".into()),
+        }
+    }
+}
+
+/// Converts a Typst value into JSON, keeping dates readable.
+fn metadata_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Datetime(datetime) => datetime
+            .display(Smart::Auto)
+            .map(|display| serde_json::Value::String(display.into()))
+            .unwrap_or(serde_json::Value::Null),
+        Value::Array(array) => array.iter().map(metadata_to_json).collect(),
+        Value::Dict(dict) => dict
+            .iter()
+            .map(|(key, value)| (key.to_string(), metadata_to_json(value)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        value => serde_json::to_value(value).unwrap_or(serde_json::Value::Null),
+    }
+}
+
+fn parse_note_metadata(title: &str, value: &Value, warnings: &mut Vec<String>) -> NoteMetadata {
+    if !matches!(value, Value::Dict(_)) {
+        warnings.push(format!(
+            "note-meta metadata of \"{title}\" must be a dictionary"
+        ));
+
+        return NoteMetadata::default();
+    }
+
+    serde_json::from_value(metadata_to_json(value)).unwrap_or_else(|error| {
+        warnings.push(format!(
+            "note-meta metadata of \"{title}\" is invalid: {error}"
+        ));
+
+        NoteMetadata::default()
+    })
+}
+
+/// The level of top-level notes in a file, which can be overridden with
+/// `#metadata(2) <note-level>`.
+fn note_level(document: &HtmlDocument, default: usize, warnings: &mut Vec<String>) -> usize {
+    let label = Label::new(PicoStr::intern("note-level")).unwrap();
+    let elements = document
+        .introspector()
+        .query(&typst::foundations::Selector::Label(label));
+
+    let Some(content) = elements.first() else {
+        return default;
+    };
+
+    match content
+        .to_packed::<MetadataElem>()
+        .map(|metadata| &metadata.value)
+    {
+        Some(Value::Int(level)) if *level >= 1 => *level as usize,
+        _ => {
+            warnings.push("note-level metadata must be a positive integer".into());
+
+            default
+        }
+    }
+}
+
+/// A top-level heading of the document, which might start a note.
+struct Section<'a> {
+    node: NodeRef<'a, Node>,
+    level: usize,
+    title: &'a str,
+    note: Option<Uuid>,
+    metadata: Option<&'a Value>,
+}
+
+struct NoteFragment {
+    title: String,
+    id: Uuid,
+    level: usize,
+    parent: Option<Uuid>,
+    children: Vec<Uuid>,
+    metadata: NoteMetadata,
+    outline: Vec<OutlineEntry>,
+    html: Html,
+}
+
+fn extract_note_fragments(
+    html: &Html,
+    headings: &[HeadingNode],
+    note_level: usize,
+    sub_notes: SubNotes,
+    warnings: &mut Vec<String>,
+) -> Vec<NoteFragment> {
+    let body = html
+        .select(&Selector::parse("body").unwrap())
+        .next()
+        .map(|body| body.id());
+    let mut seen = HashSet::new();
+    let mut sections = Vec::new();
+
+    for heading in headings {
+        // The bibliography was detached after matching, its heading is no
+        // longer part of the document.
+        let Some(node) = html
+            .tree
+            .get(heading.node)
+            .filter(|node| node.ancestors().any(|ancestor| Some(ancestor.id()) == body))
+        else {
+            continue;
+        };
+
+        let is_top_level = node.parent().map(|parent| parent.id()) == body;
+
+        let note = match heading.label.map(|label| label.resolve().parse()) {
+            Some(Ok(NoteUuid(uuid))) => {
+                if !is_top_level {
+                    warnings.push(format!(
+                        "note heading \"{}\" must not be nested inside other content, skipping it",
+                        heading.title
+                    ));
+                    None
+                } else if !seen.insert(uuid) {
+                    warnings.push(format!(
+                        "note {uuid} is defined more than once, skipping heading \"{}\"",
+                        heading.title
+                    ));
+                    None
+                } else {
+                    Some(uuid)
+                }
+            }
+            Some(Err(NoteUuidParseError::Uuid(error))) => {
+                warnings.push(format!(
+                    "heading \"{}\" has a note label with an invalid id: {error}",
+                    heading.title
+                ));
+                None
+            }
+            Some(Err(NoteUuidParseError::MissingPrefix)) | None => {
+                if is_top_level && heading.level == note_level {
+                    warnings.push(format!(
+                        "heading \"{}\" has no note label, its section isn't part of any note",
+                        heading.title
+                    ));
+                }
+                None
+            }
+        };
+
+        if note.is_none() && heading.metadata.is_some() {
+            warnings.push(format!(
+                "heading \"{}\" isn't a note, ignoring its note-meta metadata",
+                heading.title
+            ));
+        }
+
+        if is_top_level {
+            sections.push(Section {
+                node,
+                level: heading.level,
+                title: &heading.title,
+                note,
+                metadata: heading.metadata.as_ref(),
+            });
+        }
+    }
+
+    // A note is the parent of the labelled headings below it, up to the next
+    // heading at its own level or above.
+    let mut parents = vec![None; sections.len()];
+    let mut children = vec![Vec::new(); sections.len()];
+    let mut stack: Vec<usize> = Vec::new();
+
+    for (i, section) in sections.iter().enumerate() {
+        while stack
+            .last()
+            .is_some_and(|&j| sections[j].level >= section.level)
+        {
+            stack.pop();
+        }
+
+        if let Some(uuid) = section.note {
+            if let Some(&j) = stack.last() {
+                parents[i] = sections[j].note;
+                children[j].push(uuid);
+            }
+
+            stack.push(i);
+        }
+    }
+
+    let by_node: HashMap<NodeId, usize> = sections
+        .iter()
+        .enumerate()
+        .map(|(i, section)| (section.node.id(), i))
         .collect();
 
-    let header_selector = Selector::parse("body > h2").unwrap();
-    let headers = html.select(&header_selector);
+    sections
+        .iter()
+        .zip(parents)
+        .zip(children)
+        .filter_map(|((section, parent), children)| {
+            let id = section.note?;
 
-    let mut fragments: HashMap<String, Html> = headers
-        .map(|h| {
             let mut fragment = Html::new_fragment();
             let article_name = QualName::new(None, ns!(html), LocalName::from("article"));
             let article_element = scraper::node::Element::new(article_name, Vec::new());
             let mut root = fragment.tree.root_mut();
             let mut article = root.append(Node::Element(article_element));
-            let text = h.text().next().unwrap_or_default();
 
-            let siblings = h
-                .next_siblings()
-                .take_while(|&s| ElementRef::wrap(s).is_none_or(|e| e.value().name() != "h2"));
+            // While linking to a sub-note, its content is skipped up to the
+            // next heading at its level or above.
+            let mut skip_level = None;
+
+            for sibling in section.node.next_siblings() {
+                let level = ElementRef::wrap(sibling).and_then(html_heading_level);
+
+                if level.is_some_and(|level| level <= section.level) {
+                    break;
+                }
+                if level
+                    .zip(skip_level)
+                    .is_some_and(|(level, skip)| level <= skip)
+                {
+                    skip_level = None;
+                }
+                if skip_level.is_some() {
+                    continue;
+                }
+
+                if sub_notes == SubNotes::Link
+                    && let Some(child) = by_node.get(&sibling.id()).map(|&j| &sections[j])
+                    && let Some(child_id) = child.note.filter(|uuid| children.contains(uuid))
+                {
+                    article.append_subtree(sub_note_link(child_id, child.title));
+                    skip_level = Some(child.level);
+
+                    continue;
+                }
+
+                article.append_subtree(clone_subtree(sibling));
+            }
+
+            Some(NoteFragment {
+                title: section.title.to_string(),
+                id,
+                level: section.level,
+                parent,
+                children,
+                metadata: section
+                    .metadata
+                    .map(|value| parse_note_metadata(section.title, value, warnings))
+                    .unwrap_or_default(),
+                outline: Vec::new(),
+                html: fragment,
+            })
+        })
+        .collect()
+}
+
+fn sub_note_link(id: Uuid, title: &str) -> Tree<Node> {
+    let paragraph = Element::new(
+        QualName::new(None, ns!(html), LocalName::from("p")),
+        vec![Attribute {
+            name: QualName::new(
+                None,
+                markup5ever::Namespace::from(""),
+                LocalName::from("class"),
+            ),
+            value: StrTendril::from("sub-note"),
+        }],
+    );
+    let mut tree = Tree::new(Node::Element(paragraph));
+
+    let anchor = Element::new(
+        QualName::new(None, ns!(html), LocalName::from("a")),
+        vec![Attribute {
+            name: QualName::new(
+                None,
+                markup5ever::Namespace::from(""),
+                LocalName::from("href"),
+            ),
+            value: StrTendril::from(format!("note://{id}")),
+        }],
+    );
+    let mut paragraph_mut = tree.root_mut();
+    let mut anchor_mut = paragraph_mut.append(Node::Element(anchor));
+    anchor_mut.append(Node::Text(Text { text: title.into() }));
+
+    tree
+}
+
+/// Turns a heading title into an anchor id, e.g. "Proof of 2.1" into
+/// "proof-of-2-1".
+fn slugify(title: &str) -> String {
+    let mut slug = String::with_capacity(title.len());
+
+    for character in title.chars().flat_map(char::to_lowercase) {
+        if character.is_alphanumeric() {
+            slug.push(character);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    while slug.ends_with('-') {
+        slug.pop();
+    }
+
+    if slug.is_empty() {
+        "section".into()
+    } else {
+        slug
+    }
+}
+
+/// Collects the headings of a note, giving the ones without an id an anchor
+/// which is unique within the note.
+fn outline_headings(fragment: &mut Html) -> Vec<OutlineEntry> {
+    let mut ids: HashSet<String> = element_ids(fragment).into_iter().collect();
+    let headings: Vec<(NodeId, usize, String, Option<String>)> = {
+        let selector = Selector::parse(r#"h2, h3, h4, h5, h6, [role="heading"]"#).unwrap();
+
+        fragment
+            .select(&selector)
+            .filter_map(|heading| {
+                let level = html_heading_level(heading)?;
+                let title = heading.text().collect::<String>().trim().to_string();
+
+                Some((
+                    heading.id(),
+                    level,
+                    title,
+                    heading.attr("id").map(String::from),
+                ))
+            })
+            .collect()
+    };
+
+    headings
+        .into_iter()
+        .map(|(node, level, title, id)| {
+            let anchor = id.unwrap_or_else(|| {
+                let slug = slugify(&title);
+                let anchor = (1..)
+                    .map(|n| match n {
+                        1 => slug.clone(),
+                        n => format!("{slug}-{n}"),
+                    })
+                    .find(|anchor| !ids.contains(anchor))
+                    .unwrap();
+
+                if let Node::Element(element) = fragment.tree.get_mut(node).unwrap().value() {
+                    element.attrs.push((
+                        QualName::new(
+                            None,
+                            markup5ever::Namespace::from(""),
+                            LocalName::from("id"),
+                        ),
+                        StrTendril::from(anchor.as_str()),
+                    ));
+                }
+                ids.insert(anchor.clone());
+
+                anchor
+            });
 
-            for sibling in siblings {
-                article.append_subtree(clone_subtree(sibling));
+            OutlineEntry {
+                level,
+                title,
+                anchor,
             }
-
-            (text.into(), fragment)
         })
-        .collect();
+        .collect()
+}
+
+/// Renders an outline as nested lists inside a `<nav>`.
+fn table_of_contents(outline: &[OutlineEntry]) -> Tree<Node> {
+    fn element(name: &str, attributes: &[(&str, &str)]) -> Node {
+        Node::Element(Element::new(
+            QualName::new(None, ns!(html), LocalName::from(name)),
+            attributes
+                .iter()
+                .map(|(name, value)| Attribute {
+                    name: QualName::new(
+                        None,
+                        markup5ever::Namespace::from(""),
+                        LocalName::from(*name),
+                    ),
+                    value: StrTendril::from(*value),
+                })
+                .collect(),
+        ))
+    }
 
-    // Note: This check is not sufficient. We should check injectivity of both
-    // partial maps or something, I don't know. Point being, terrible things
-    // might happen but I just don't really care unless this bites me later
-    assert_eq!(matches.len(), fragments.len());
+    let mut tree = Tree::new(element("nav", &[("class", "table-of-contents")]));
+    let list = tree.root_mut().append(element("ol", &[])).id();
 
-    matches
-        .into_iter()
-        .filter_map(|(uuid, title)| {
-            fragments
-                .remove(&title)
-                .map(|fragment| (title, uuid, fragment))
-        })
-        .collect()
+    // The open lists together with the level of their items and their last
+    // item
+    let mut stack: Vec<(usize, NodeId, Option<NodeId>)> =
+        vec![(outline.first().map_or(1, |entry| entry.level), list, None)];
+
+    for entry in outline {
+        while stack.len() > 1 && stack[stack.len() - 2].0 >= entry.level {
+            stack.pop();
+        }
+        let (level, _, last_item) = stack.last_mut().unwrap();
+        // Entries between two levels, or above the first entry, are siblings
+        // of the list's items
+        *level = (*level).min(entry.level);
+        if let Some(item) = *last_item
+            && entry.level > *level
+        {
+            let list = tree.get_mut(item).unwrap().append(element("ol", &[])).id();
+            stack.push((entry.level, list, None));
+        }
+
+        let (_, list, last_item) = stack.last_mut().unwrap();
+        let mut list = tree.get_mut(*list).unwrap();
+        let mut item = list.append(element("li", &[]));
+        let href = format!("#{}", entry.anchor);
+        item.append(element("a", &[("href", &href)]))
+            .append(Node::Text(Text {
+                text: entry.title.as_str().into(),
+            }));
+        *last_item = Some(item.id());
+    }
+
+    tree
 }
 
 fn extract_bibliography(html: &mut Html) -> HashMap<String, Tree<Node>> {
@@ -619,13 +1921,370 @@ fn attach_bibliography(fragment: &mut Html, bibliography: &HashMap<String, Tree<
     }
 }
 
+fn transclusion_element(id: Uuid, class: &str) -> Tree<Node> {
+    let div = Element::new(
+        QualName::new(None, ns!(html), LocalName::from("div")),
+        vec![
+            Attribute {
+                name: QualName::new(
+                    None,
+                    markup5ever::Namespace::from(""),
+                    LocalName::from("class"),
+                ),
+                value: StrTendril::from(class),
+            },
+            Attribute {
+                name: QualName::new(
+                    None,
+                    markup5ever::Namespace::from(""),
+                    LocalName::from("data-note"),
+                ),
+                value: StrTendril::from(id.to_string()),
+            },
+        ],
+    );
+
+    Tree::new(Node::Element(div))
+}
+
+/// Replaces every `transclude://` link in a fragment with the rendered
+/// content of the target note, expanding the target's own transclusions
+/// along the way. `stack` holds the notes currently being expanded, and every
+/// note whose content was asked for ends up in `transclusions`.
+fn expand_transclusions(
+    fragment: &mut Html,
+    fragments: &HashMap<Uuid, String>,
+    stack: &mut Vec<Uuid>,
+    transclusions: &mut HashSet<Uuid>,
+    warnings: &mut Vec<String>,
+) {
+    let selector = Selector::parse(r#"a[href^="transclude:"]"#).unwrap();
+    let anchors: Vec<(NodeId, Uuid)> = fragment
+        .select(&selector)
+        .filter_map(|anchor| {
+            let TranscludeLink(target) = anchor.attr("href")?.parse().ok()?;
+
+            Some((anchor.id(), target))
+        })
+        .collect();
+
+    for (anchor, target) in anchors {
+        transclusions.insert(target);
+
+        let replacement = if stack.contains(&target) {
+            let cycle = stack
+                .iter()
+                .skip_while(|&&id| id != target)
+                .chain([&target])
+                .map(Uuid::to_string)
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            warnings.push(format!("transclusion cycle: {cycle}"));
+
+            let mut tree = transclusion_element(target, "transclusion-error");
+            tree.root_mut().append(Node::Text(Text {
+                text: "Transclusion cycle".into(),
+            }));
+
+            tree
+        } else if let Some(content) = fragments.get(&target) {
+            let mut html = Html::parse_fragment(content);
+
+            stack.push(target);
+            expand_transclusions(&mut html, fragments, stack, transclusions, warnings);
+            stack.pop();
+            prefix_ids(&mut html, &target.to_string());
+
+            let mut tree = transclusion_element(target, "transclusion");
+            let selector = Selector::parse("article").unwrap();
+            if let Some(article) = html.select(&selector).next() {
+                for child in article.children() {
+                    tree.root_mut().append_subtree(clone_subtree(child));
+                }
+            }
+
+            tree
+        } else {
+            warnings.push(format!("transcluded note {target} doesn't exist"));
+
+            let mut tree = transclusion_element(target, "transclusion-error");
+            tree.root_mut().append(Node::Text(Text {
+                text: "Missing note".into(),
+            }));
+
+            tree
+        };
+
+        // A link on its own line ends up alone in a paragraph, which can't
+        // contain the transcluded blocks, so the paragraph is replaced.
+        let node = fragment.tree.get(anchor).unwrap();
+        let replaced = node
+            .parent()
+            .filter(|parent| {
+                ElementRef::wrap(*parent).is_some_and(|element| element.value().name() == "p")
+                    && parent.children().all(|child| {
+                        child.id() == anchor
+                            || child
+                                .value()
+                                .as_text()
+                                .is_some_and(|text| text.trim().is_empty())
+                    })
+            })
+            .map_or(anchor, |parent| parent.id());
+
+        let replacement = fragment.tree.extend_tree(replacement).id();
+        let mut replaced = fragment.tree.get_mut(replaced).unwrap();
+        replaced.insert_id_before(replacement);
+        replaced.detach();
+    }
+}
+
+/// Prefixes every id of transcluded content, along with the in-page anchors
+/// pointing at them, so that they don't clash with the host note's ids.
+fn prefix_ids(html: &mut Html, prefix: &str) {
+    let ids: HashSet<String> = element_ids(html).into_iter().collect();
+    let nodes: Vec<NodeId> = html.tree.nodes().map(|node| node.id()).collect();
+
+    for node in nodes {
+        if let Node::Element(element) = html.tree.get_mut(node).unwrap().value() {
+            for (name, value) in element.attrs.iter_mut() {
+                let renamed = match &*name.local {
+                    "id" => Some(format!("{prefix}-{value}")),
+                    "href" => value
+                        .strip_prefix('#')
+                        .filter(|target| ids.contains(*target))
+                        .map(|target| format!("#{prefix}-{target}")),
+                    _ => None,
+                };
+                if let Some(renamed) = renamed {
+                    *value = StrTendril::from(renamed.as_str());
+                }
+            }
+        }
+    }
+}
+
+/// Sets an attribute of an element, adding it if it's missing.
+fn set_attribute(mut node: ego_tree::NodeMut<'_, Node>, name: &str, value: &str) {
+    let Node::Element(element) = node.value() else {
+        return;
+    };
+
+    match element
+        .attrs
+        .iter_mut()
+        .find(|(other, _)| &*other.local == name)
+    {
+        Some((_, current)) => *current = StrTendril::from(value),
+        None => element.attrs.push((
+            QualName::new(
+                None,
+                markup5ever::Namespace::from(""),
+                LocalName::from(name),
+            ),
+            StrTendril::from(value),
+        )),
+    }
+}
+
+fn extract_footnotes(html: &mut Html) -> HashMap<String, Tree<Node>> {
+    let selector = Selector::parse(r#"section[role="doc-endnotes"] > ol > li"#).unwrap();
+    let entries = html.select(&selector);
+
+    let footnotes = entries
+        .filter_map(|element| {
+            let id: String = element.attr("id")?.into();
+
+            Some((id, clone_subtree(*element)))
+        })
+        .collect();
+
+    // Transcluded notes bring their own sections
+    let selector = Selector::parse(r#"section[role="doc-endnotes"]"#).unwrap();
+    let sections: Vec<NodeId> = html.select(&selector).map(|element| element.id()).collect();
+    for id in sections {
+        html.tree.get_mut(id).unwrap().detach();
+    }
+
+    footnotes
+}
+
+/// Replaces the text of the first text node below `node`.
+fn set_first_text<'a>(mut node: ego_tree::NodeMut<'a, Node>, text: &str) {
+    loop {
+        if let Node::Text(Text { text: current }) = node.value() {
+            *current = text.into();
+
+            return;
+        }
+
+        match node.into_first_child() {
+            Ok(child) => node = child,
+            Err(_) => return,
+        }
+    }
+}
+
+fn attach_footnotes(fragment: &mut Html, footnotes: &HashMap<String, Tree<Node>>) {
+    let references: Vec<(NodeId, String)> = {
+        let selector = Selector::parse(r#"a[role="doc-noteref"]"#).unwrap();
+
+        fragment
+            .select(&selector)
+            .filter_map(|anchor| {
+                let href = anchor.attr("href")?.trim_start_matches("#");
+
+                Some((anchor.id(), href.into()))
+            })
+            .collect()
+    };
+
+    // Footnotes are numbered by their first reference within the note
+    let mut firsts: Vec<(&str, NodeId)> = Vec::new();
+    for (node, id) in &references {
+        let number = match firsts.iter().position(|(other, _)| other == id) {
+            Some(index) => index + 1,
+            None => {
+                firsts.push((id, *node));
+                firsts.len()
+            }
+        };
+
+        set_first_text(fragment.tree.get_mut(*node).unwrap(), &number.to_string());
+    }
+
+    // Entries link back to their first reference within the note, the one
+    // they were written for might belong to another note
+    let entries: Vec<(Tree<Node>, String)> = firsts
+        .iter()
+        .filter_map(|&(id, reference)| {
+            let entry = footnotes.get(id)?.clone();
+            let anchor = ElementRef::wrap(fragment.tree.get(reference).unwrap())
+                .and_then(|element| element.attr("id"))
+                .map(String::from)
+                .unwrap_or_else(|| {
+                    let anchor = format!("{id}-ref");
+                    set_attribute(fragment.tree.get_mut(reference).unwrap(), "id", &anchor);
+
+                    anchor
+                });
+
+            Some((entry, anchor))
+        })
+        .collect();
+
+    if !entries.is_empty() {
+        let section = {
+            let section = Element::new(
+                QualName::new(None, ns!(html), LocalName::from("section")),
+                vec![Attribute {
+                    name: QualName::new(
+                        None,
+                        markup5ever::Namespace::from(""),
+                        LocalName::from("role"),
+                    ),
+                    value: StrTendril::from("doc-endnotes"),
+                }],
+            );
+            let mut tree = Tree::new(Node::Element(section));
+            let mut section_mut = tree.root_mut();
+
+            let ol = Element::new(
+                QualName::new(None, ns!(html), LocalName::from("ol")),
+                vec![Attribute {
+                    name: QualName::new(
+                        None,
+                        markup5ever::Namespace::from(""),
+                        LocalName::from("style"),
+                    ),
+                    value: StrTendril::from("list-style-type: none"),
+                }],
+            );
+            let mut ol_mut = section_mut.append(Node::Element(ol));
+
+            for (number, (mut entry, anchor)) in (1..).zip(entries) {
+                // The back reference is the first link of the entry
+                let backlink = entry
+                    .root()
+                    .descendants()
+                    .find(|node| {
+                        ElementRef::wrap(*node)
+                            .is_some_and(|element| element.attr("role") == Some("doc-backlink"))
+                    })
+                    .map(|node| node.id());
+                if let Some(backlink) = backlink {
+                    set_attribute(
+                        entry.get_mut(backlink).unwrap(),
+                        "href",
+                        &format!("#{anchor}"),
+                    );
+                    set_first_text(entry.get_mut(backlink).unwrap(), &number.to_string());
+                }
+
+                ol_mut.append_subtree(entry);
+            }
+
+            tree
+        };
+        let selector = Selector::parse("article").unwrap();
+        let article_id = fragment.select(&selector).next().unwrap().id();
+
+        fragment
+            .tree
+            .get_mut(article_id)
+            .unwrap()
+            .append_subtree(section);
+    }
+}
+
+fn element_ids(html: &Html) -> Vec<String> {
+    let selector = Selector::parse("[id]").unwrap();
+
+    html.select(&selector)
+        .filter_map(|element| element.attr("id").map(String::from))
+        .collect()
+}
+
+/// Turns in-page anchors which point into another note of the same file into
+/// note links, since the target no longer lives on the same page once the
+/// file is split.
+fn rewrite_anchors(fragment: &mut Html, owners: &HashMap<String, Uuid>) {
+    let local: HashSet<String> = element_ids(fragment).into_iter().collect();
+    let anchors: Vec<(NodeId, String)> = {
+        let selector = Selector::parse(r##"a[href^="#"]"##).unwrap();
+
+        fragment
+            .select(&selector)
+            .filter_map(|anchor| {
+                let target = anchor.attr("href")?.trim_start_matches('#');
+                if local.contains(target) {
+                    return None;
+                }
+                let owner = owners.get(target)?;
+
+                Some((anchor.id(), format!("note://{}#{}", owner, target)))
+            })
+            .collect()
+    };
+
+    for (id, href) in anchors {
+        if let Node::Element(element) = fragment.tree.get_mut(id).unwrap().value() {
+            for (name, value) in element.attrs.iter_mut() {
+                if &*name.local == "href" {
+                    *value = StrTendril::from(href.as_str());
+                }
+            }
+        }
+    }
+}
+
 fn find_links(html: &Html) -> Vec<Uuid> {
     let selector = Selector::parse("a").unwrap();
 
     html.select(&selector)
         .filter_map(|element| {
             element.attr("href").and_then(|href| {
-                if let Ok(NoteLink(uuid)) = href.parse() {
+                if let Ok(NoteLink(uuid, _)) = href.parse() {
                     Some(uuid)
                 } else {
                     None
@@ -635,12 +2294,17 @@ fn find_links(html: &Html) -> Vec<Uuid> {
         .collect()
 }
 
-fn upgrade_headings(html: &mut Html) {
+/// Shifts the headings of a note's content so that they are numbered
+/// relative to the note, whose own title takes the place of `h1`.
+fn upgrade_headings(html: &mut Html, note_level: usize) {
     let selector = Selector::parse("h2, h3, h4, h5, h6").unwrap();
     // TODO: Make this more efficient
-    let ids: Vec<_> = html.select(&selector).map(|element| element.id()).collect();
+    let headings: Vec<_> = html
+        .select(&selector)
+        .filter_map(|element| Some((element.id(), html_heading_level(element)?)))
+        .collect();
 
-    for id in ids {
+    for (id, level) in headings {
         let Some(mut node) = html.tree.get_mut(id) else {
             continue;
         };
@@ -648,26 +2312,75 @@ fn upgrade_headings(html: &mut Html) {
             continue;
         };
 
-        match element.name.local.as_ref() {
-            "h2" => element.name.local = LocalName::from("h1"),
-            "h3" => element.name.local = LocalName::from("h2"),
-            "h4" => element.name.local = LocalName::from("h3"),
-            "h5" => element.name.local = LocalName::from("h4"),
-            "h6" => element.name.local = LocalName::from("h5"),
-            _ => continue,
-        }
+        let tag = (level + 1).saturating_sub(note_level).max(1);
+        element.name.local = LocalName::from(format!("h{tag}"));
     }
 }
 
-type BuildOutputs = (Vec<String>, Vec<NoteData>, HashSet<FileId>);
-
-async fn build<S>(
+/// Everything a build needs that is shared between builds.
+#[derive(Clone)]
+struct BuildContext<S> {
     resources: Arc<Resources>,
+    files: Arc<dyn FileSource>,
     package_storage: PackageStorage<S>,
     slots: Arc<Mutex<HashMap<FileId, FileSlot>>>,
+    overlay: Overlay,
+    note_config: NoteConfig,
+    fragments: Arc<Mutex<HashMap<Uuid, String>>>,
+    build_subdirectory: Arc<PathBuf>,
+    metrics: Arc<Metrics>,
+    /// Cancelled when the result is no longer wanted because the inputs
+    /// changed while compiling.
+    stale: CancellationToken,
+}
+
+/// A readable name for a file, relative to the project or prefixed by its
+/// package.
+fn file_name(id: FileId) -> String {
+    match id.package() {
+        Some(package) => format!("{package}{}", id.vpath().as_rooted_path().display()),
+        None => id.vpath().as_rootless_path().display().to_string(),
+    }
+}
+
+/// Writes a rendered note to the build directory, recreating the directory if
+/// it was removed.
+async fn write_fragment(
     build_subdirectory: Arc<PathBuf>,
+    id: Uuid,
+    content: String,
+) -> Result<(), io::Error> {
+    let path = build_subdirectory.join(format!("{}.html", id));
+    let (directory, path, content) = (build_subdirectory.as_ref(), &path, &content);
+
+    retry(move || async move {
+        match fs::write(path, content).await {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir_all(directory).await?;
+                fs::write(path, content).await
+            }
+            result => result,
+        }
+    })
+    .await
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(file = %file_name(main_id)))]
+async fn build<S>(
+    BuildContext {
+        resources,
+        files,
+        package_storage,
+        slots,
+        overlay,
+        note_config,
+        fragments: raw_fragments,
+        build_subdirectory,
+        metrics,
+        stale,
+    }: BuildContext<S>,
     main_id: FileId,
-) -> Result<Result<BuildOutputs, Vec<String>>, io::Error>
+) -> (BuildResult, HashSet<FileId>)
 where
     S: Send + Sync + 'static,
     S: PackageService,
@@ -675,46 +2388,571 @@ where
     PackageError: From<S::GetPackageServiceError>,
     S::GetPackageBuffer: Buf,
 {
-    let result = tokio::task::spawn_blocking(move || {
-        let (warnings, (mut html, document, dependencies)) =
-            compile(resources, package_storage, slots, main_id)?;
-        // Need to remove bibliography section before note fragments get cloned as subtrees
-        let bibliography = extract_bibliography(&mut html);
-        let fragments = extract_note_fragments(&html, &document);
-        let (outputs, writes) = fragments
-            .into_iter()
-            .map(|(title, id, mut fragment)| {
-                upgrade_headings(&mut fragment);
-                attach_bibliography(&mut fragment, &bibliography);
+    let start = Instant::now();
+    let span = Span::current();
+    let (result, dependencies) = tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        if stale.is_cancelled() {
+            return (Err(Vec::new()), HashSet::new());
+        }
+        let (result, dependencies) = compile(
+            resources,
+            files,
+            package_storage,
+            slots,
+            overlay,
+            stale.clone(),
+            main_id,
+        );
+        // Each phase is skipped once nobody is waiting for the result anymore
+        if stale.is_cancelled() {
+            return (Err(Vec::new()), HashSet::new());
+        }
+        let result = result.and_then(|(mut warnings, (mut html, document))| {
+            // Headings are matched against the introspector while the HTML is
+            // still complete
+            let mut headings = match_headings(&html, &document).map_err(|error| vec![error])?;
+            assign_note_metadata(&document, &mut headings, &mut warnings);
+            let note_level = note_level(&document, note_config.level, &mut warnings);
+            // Need to remove bibliography and footnote sections before note
+            // fragments get cloned as subtrees
+            let bibliography = extract_bibliography(&mut html);
+            let footnotes = extract_footnotes(&mut html);
+            let mut fragments = extract_note_fragments(
+                &html,
+                &headings,
+                note_level,
+                note_config.sub_notes,
+                &mut warnings,
+            );
+            for fragment in &mut fragments {
+                upgrade_headings(&mut fragment.html, fragment.level);
+                attach_footnotes(&mut fragment.html, &footnotes);
+                attach_bibliography(&mut fragment.html, &bibliography);
+                fragment.outline = outline_headings(&mut fragment.html);
+            }
+            if stale.is_cancelled() {
+                return Err(Vec::new());
+            }
+
+            // Embedded sub-notes come after their parent, so the innermost note
+            // ends up owning an id
+            let mut owners = HashMap::new();
+            for fragment in &fragments {
+                for anchor in element_ids(&fragment.html) {
+                    owners.insert(anchor, fragment.id);
+                }
+            }
+            for fragment in &mut fragments {
+                rewrite_anchors(&mut fragment.html, &owners);
+            }
+
+            // All of the file's fragments need to be known before expanding, a
+            // note might transclude another note from the same file
+            let mut raw_fragments = raw_fragments.lock();
+            // Checked while holding the lock, so that a stale compile can't
+            // overwrite the fragments of the compile which replaced it. Nobody is
+            // waiting for the result anymore.
+            if stale.is_cancelled() {
+                return Err(Vec::new());
+            }
+            for fragment in &fragments {
+                raw_fragments.insert(fragment.id, fragment.html.html());
+            }
 
-                let links = find_links(&fragment);
-                let output = NoteData { title, id, links };
+            let (outputs, writes) = fragments
+                .into_iter()
+                .map(|fragment| {
+                    let NoteFragment {
+                        title,
+                        id,
+                        parent,
+                        children,
+                        metadata,
+                        outline,
+                        html: mut fragment,
+                        ..
+                    } = fragment;
+
+                    let links = find_links(&fragment);
+
+                    let mut transclusions = HashSet::new();
+                    expand_transclusions(
+                        &mut fragment,
+                        &raw_fragments,
+                        &mut vec![id],
+                        &mut transclusions,
+                        &mut warnings,
+                    );
+                    if !transclusions.is_empty() {
+                        // The transcluded notes' footnotes are numbered along
+                        // with the note's own, in a single section
+                        let footnotes = extract_footnotes(&mut fragment);
+                        attach_footnotes(&mut fragment, &footnotes);
+                    }
 
-                let content = fragment.html();
-                let path = build_subdirectory.join(format!("{}.html", id));
-                let write = fs::write(path, content);
+                    let output = NoteData {
+                        title,
+                        id,
+                        links,
+                        parent,
+                        children,
+                        transclusions: transclusions.into_iter().collect(),
+                        metadata,
+                        outline: outline.clone(),
+                        error: None,
+                    };
+
+                    if note_config.table_of_contents && !outline.is_empty() {
+                        let selector = Selector::parse("article").unwrap();
+                        let article_id = fragment.select(&selector).next().unwrap().id();
+
+                        fragment
+                            .tree
+                            .get_mut(article_id)
+                            .unwrap()
+                            .prepend_subtree(table_of_contents(&outline));
+                    }
 
-                (output, write)
-            })
-            .unzip::<_, _, Vec<_>, Vec<_>>();
+                    let write = write_fragment(build_subdirectory.clone(), id, fragment.html());
 
-        Ok((warnings, outputs, writes, dependencies))
+                    (output, write)
+                })
+                .unzip::<_, _, Vec<_>, Vec<_>>();
+
+            Ok((warnings, outputs, writes))
+        });
+
+        (result, dependencies)
     })
     .await
     // If code in this task panics, we should panic
     .unwrap();
 
     match result {
-        Ok((warnings, outputs, writes, dependencies)) => {
-            futures::future::join_all(writes)
-                .await
+        Ok((warnings, mut outputs, writes)) => {
+            let results = futures::future::join_all(writes).await;
+            for (output, result) in outputs.iter_mut().zip(results) {
+                if let Err(error) = result {
+                    error!(%error, note = %output.id, "failed to write fragment to the build directory");
+                    output.error = Some(format!(
+                        "couldn't write the note to the build directory: {error}"
+                    ));
+                }
+            }
+
+            metrics.observe_compile(file_name(main_id), start.elapsed());
+            debug!(
+                duration_ms = start.elapsed().as_millis(),
+                notes = outputs.len(),
+                dependencies = dependencies.len(),
+                warnings = warnings.len(),
+                "built file"
+            );
+
+            (Ok((warnings, outputs)), dependencies)
+        }
+        Err(errors) => {
+            metrics.observe_compile(file_name(main_id), start.elapsed());
+            debug!(
+                duration_ms = start.elapsed().as_millis(),
+                errors = errors.len(),
+                "file failed to compile"
+            );
+
+            (Err(errors), dependencies)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn first_element<'a>(html: &'a Html, selector: &str) -> ElementRef<'a> {
+        html.select(&Selector::parse(selector).unwrap())
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn heading_levels_start_below_the_title() {
+        let html = Html::parse_fragment(
+            r#"<h2>A</h2><h6>B</h6><div role="heading" aria-level="7">C</div><p>D</p>"#,
+        );
+
+        assert_eq!(html_heading_level(first_element(&html, "h2")), Some(1));
+        assert_eq!(html_heading_level(first_element(&html, "h6")), Some(5));
+        assert_eq!(html_heading_level(first_element(&html, "div")), Some(6));
+        assert_eq!(html_heading_level(first_element(&html, "p")), None);
+    }
+
+    #[test]
+    fn invalid_aria_levels_match_nothing() {
+        for level in ["0", "-1", "high"] {
+            let html = Html::parse_fragment(&format!(
+                r#"<div role="heading" aria-level="{level}">A</div>"#
+            ));
+
+            assert_eq!(html_heading_level(first_element(&html, "div")), None);
+        }
+    }
+
+    /// Labels the headings of a document in order, like `match_headings`
+    /// does with what the introspector knows.
+    fn headings(html: &Html, notes: &[Option<u128>]) -> Vec<HeadingNode> {
+        let selector = Selector::parse(r#"h2, h3, h4, h5, h6, [role="heading"]"#).unwrap();
+
+        html.select(&selector)
+            .zip(notes)
+            .map(|(header, note)| HeadingNode {
+                node: header.id(),
+                label: note.map(|note| {
+                    let label = format!("note:{}", Uuid::from_u128(note));
+                    Label::new(PicoStr::intern(&label)).unwrap()
+                }),
+                level: html_heading_level(header).unwrap(),
+                title: header.text().collect(),
+                metadata: None,
+            })
+            .collect()
+    }
+
+    fn fragments(
+        document: &str,
+        notes: &[Option<u128>],
+        sub_notes: SubNotes,
+    ) -> (Vec<NoteFragment>, Vec<String>) {
+        let html = Html::parse_document(document);
+        let headings = headings(&html, notes);
+        let mut warnings = Vec::new();
+        let fragments = extract_note_fragments(&html, &headings, 1, sub_notes, &mut warnings);
+
+        (fragments, warnings)
+    }
+
+    const NESTED: &str = "<h2>A</h2><p>a</p><h3>B</h3><p>b</p><h4>Plain</h4><p>plain</p>\
+        <h4>C</h4><p>c</p><h3>D</h3><p>d</p><h2>E</h2><p>e</p>";
+    const NESTED_NOTES: [Option<u128>; 6] = [Some(1), Some(2), None, Some(3), Some(4), Some(5)];
+
+    #[test]
+    fn sub_notes_belong_to_the_closest_note_above() {
+        let (fragments, warnings) = fragments(NESTED, &NESTED_NOTES, SubNotes::Embed);
+        let hierarchy: Vec<(Uuid, Option<Uuid>, Vec<Uuid>)> = fragments
+            .iter()
+            .map(|fragment| (fragment.id, fragment.parent, fragment.children.clone()))
+            .collect();
+        let id = Uuid::from_u128;
+
+        assert!(warnings.is_empty());
+        assert_eq!(
+            hierarchy,
+            vec![
+                (id(1), None, vec![id(2), id(4)]),
+                (id(2), Some(id(1)), vec![id(3)]),
+                (id(3), Some(id(2)), vec![]),
+                (id(4), Some(id(1)), vec![]),
+                (id(5), None, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn embedded_sub_notes_stay_in_their_parent() {
+        let (fragments, _) = fragments(NESTED, &NESTED_NOTES, SubNotes::Embed);
+        let text: String = fragments[0].html.root_element().text().collect();
+
+        assert_eq!(text, "aBbPlainplainCcDd");
+    }
+
+    #[test]
+    fn linked_sub_notes_are_replaced_by_links() {
+        let (fragments, _) = fragments(NESTED, &NESTED_NOTES, SubNotes::Link);
+        let links = |fragment: &NoteFragment| -> Vec<String> {
+            fragment
+                .html
+                .select(&Selector::parse("p.sub-note > a").unwrap())
+                .filter_map(|link| link.attr("href").map(String::from))
+                .collect()
+        };
+
+        let text: String = fragments[0].html.root_element().text().collect();
+        assert_eq!(text, "aBD");
+        assert_eq!(
+            links(&fragments[0]),
+            [2, 4].map(|id| format!("note://{}", Uuid::from_u128(id)))
+        );
+
+        // The plain heading stays, only the sub-note after it is skipped
+        let text: String = fragments[1].html.root_element().text().collect();
+        assert_eq!(text, "bPlainplainC");
+    }
+
+    #[test]
+    fn nested_and_repeated_note_headings_are_skipped() {
+        let (fragments, warnings) = fragments(
+            "<h2>A</h2><div><h2>Nested</h2></div><h2>Again</h2>",
+            &[Some(1), Some(2), Some(1)],
+            SubNotes::Embed,
+        );
+
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("\"Nested\" must not be nested"));
+        assert!(warnings[1].contains("defined more than once"));
+    }
+
+    /// A note's fragment with some text, followed by a transclusion of each
+    /// of `targets`.
+    fn transcluding(text: &str, targets: &[u128]) -> String {
+        let links: String = targets
+            .iter()
+            .map(|&target| {
+                format!(
+                    r#"<p><a href="transclude://{}">x</a></p>"#,
+                    Uuid::from_u128(target)
+                )
+            })
+            .collect();
+
+        format!("<article><p>{text}</p>{links}</article>")
+    }
+
+    fn expand(id: u128, fragments: &[(u128, String)]) -> (Html, HashSet<Uuid>, Vec<String>) {
+        let fragments: HashMap<Uuid, String> = fragments
+            .iter()
+            .map(|(id, fragment)| (Uuid::from_u128(*id), fragment.clone()))
+            .collect();
+        let mut html = Html::parse_fragment(&fragments[&Uuid::from_u128(id)]);
+        let (mut transclusions, mut warnings) = (HashSet::new(), Vec::new());
+
+        expand_transclusions(
+            &mut html,
+            &fragments,
+            &mut vec![Uuid::from_u128(id)],
+            &mut transclusions,
+            &mut warnings,
+        );
+
+        (html, transclusions, warnings)
+    }
+
+    #[test]
+    fn transclusions_expand_recursively() {
+        let (html, transclusions, warnings) = expand(
+            1,
+            &[
+                (1, transcluding("a", &[2])),
+                (2, transcluding("b", &[3])),
+                (3, transcluding("c", &[])),
+            ],
+        );
+        let text: String = html.root_element().text().collect();
+
+        assert!(warnings.is_empty());
+        assert_eq!(text, "abc");
+        assert_eq!(transclusions, HashSet::from([2, 3].map(Uuid::from_u128)));
+        // The link paragraphs were replaced
+        assert_eq!(html.select(&Selector::parse("p > div").unwrap()).count(), 0);
+    }
+
+    #[test]
+    fn transclusion_cycles_are_cut() {
+        let (html, transclusions, warnings) = expand(
+            1,
+            &[
+                (1, transcluding("a", &[2])),
+                (2, transcluding("b", &[3])),
+                (3, transcluding("c", &[2])),
+            ],
+        );
+        let errors: Vec<String> = html
+            .select(&Selector::parse(".transclusion-error").unwrap())
+            .map(|error| error.text().collect())
+            .collect();
+        let (b, c) = (Uuid::from_u128(2), Uuid::from_u128(3));
+
+        assert_eq!(errors, ["Transclusion cycle"]);
+        assert_eq!(warnings, [format!("transclusion cycle: {b} -> {c} -> {b}")]);
+        assert_eq!(transclusions, HashSet::from([b, c]));
+    }
+
+    #[test]
+    fn transcluding_itself_is_a_cycle() {
+        let (_, _, warnings) = expand(1, &[(1, transcluding("a", &[1]))]);
+        let a = Uuid::from_u128(1);
+
+        assert_eq!(warnings, [format!("transclusion cycle: {a} -> {a}")]);
+    }
+
+    #[test]
+    fn the_same_note_can_be_transcluded_twice() {
+        let (html, _, warnings) = expand(
+            1,
+            &[(1, transcluding("a", &[2, 2])), (2, transcluding("b", &[]))],
+        );
+        let text: String = html.root_element().text().collect();
+
+        assert!(warnings.is_empty());
+        assert_eq!(text, "abb");
+    }
+
+    #[test]
+    fn missing_transclusions_are_reported() {
+        let (html, transclusions, warnings) = expand(1, &[(1, transcluding("a", &[2]))]);
+        let error = html
+            .select(&Selector::parse(".transclusion-error").unwrap())
+            .next()
+            .unwrap();
+
+        assert_eq!(error.text().collect::<String>(), "Missing note");
+        assert_eq!(
+            warnings,
+            [format!(
+                "transcluded note {} doesn't exist",
+                Uuid::from_u128(2)
+            )]
+        );
+        assert_eq!(transclusions, HashSet::from([Uuid::from_u128(2)]));
+    }
+
+    fn dict(entries: Vec<(&str, Value)>) -> Value {
+        Value::Dict(
+            entries
                 .into_iter()
-                // `try_for_each` assumes you're calling an effect. For us, we
-                // just want to check if all writes succeeded.
-                .try_for_each(|result| result)?;
+                .map(|(key, value)| (key.into(), value))
+                .collect(),
+        )
+    }
+
+    fn string(value: &str) -> Value {
+        Value::Str(value.into())
+    }
+
+    #[test]
+    fn metadata_fills_known_fields_and_keeps_the_rest() {
+        let created = typst::foundations::Datetime::from_ymd(2024, 1, 2).unwrap();
+        let value = dict(vec![
+            (
+                "tags",
+                Value::Array([string("draft"), string("maths")].into_iter().collect()),
+            ),
+            ("created", Value::Datetime(created)),
+            ("status", string("seed")),
+            ("rating", Value::Int(3)),
+            ("source", dict(vec![("page", Value::Int(12))])),
+        ]);
+        let mut warnings = Vec::new();
+
+        let metadata = parse_note_metadata("A", &value, &mut warnings);
+
+        assert!(warnings.is_empty());
+        assert_eq!(
+            metadata,
+            NoteMetadata {
+                tags: vec!["draft".into(), "maths".into()],
+                aliases: Vec::new(),
+                created: Some("2024-01-02".into()),
+                status: Some("seed".into()),
+                extra: BTreeMap::from([
+                    ("rating".into(), serde_json::json!(3)),
+                    ("source".into(), serde_json::json!({ "page": 12 })),
+                ]),
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_metadata_is_dropped_with_a_warning() {
+        for (value, warning) in [
+            (
+                string("draft"),
+                "note-meta metadata of \"A\" must be a dictionary",
+            ),
+            (
+                dict(vec![("tags", Value::Int(1))]),
+                "note-meta metadata of \"A\" is invalid",
+            ),
+        ] {
+            let mut warnings = Vec::new();
+
+            let metadata = parse_note_metadata("A", &value, &mut warnings);
+
+            assert_eq!(metadata, NoteMetadata::default());
+            assert_eq!(warnings.len(), 1);
+            assert!(warnings[0].starts_with(warning), "{}", warnings[0]);
+        }
+    }
+
+    #[test]
+    fn slugs_are_lowercase_words() {
+        assert_eq!(slugify("Proof of 2.1"), "proof-of-2-1");
+        assert_eq!(slugify("  Über  Größe! "), "über-größe");
+        assert_eq!(slugify("?!"), "section");
+    }
 
-            Ok(Ok((warnings, outputs, dependencies)))
+    #[test]
+    fn outline_anchors_are_unique() {
+        let mut html = Html::parse_fragment(
+            r#"<article><p id="intro">Text</p><h2>Intro</h2><h3>Intro</h3><h2 id="kept">Kept</h2><h2>?</h2></article>"#,
+        );
+
+        let outline = outline_headings(&mut html);
+        let entries: Vec<(usize, &str, &str)> = outline
+            .iter()
+            .map(|entry| (entry.level, entry.title.as_str(), entry.anchor.as_str()))
+            .collect();
+
+        assert_eq!(
+            entries,
+            [
+                (1, "Intro", "intro-2"),
+                (2, "Intro", "intro-3"),
+                (1, "Kept", "kept"),
+                (1, "?", "section"),
+            ]
+        );
+        // The headings can be linked to
+        for entry in &outline {
+            assert!(element_ids(&html).contains(&entry.anchor));
         }
-        Err(errors) => Ok(Err(errors)),
+    }
+
+    #[test]
+    fn table_of_contents_nests_by_level() {
+        let entry = |level, title: &str| OutlineEntry {
+            level,
+            title: title.into(),
+            anchor: slugify(title),
+        };
+        let outline = [
+            entry(2, "A"),
+            entry(3, "A.1"),
+            entry(4, "A.1.1"),
+            entry(2, "B"),
+            entry(4, "B.1"),
+            entry(3, "B.2"),
+            entry(1, "C"),
+        ];
+
+        let mut html = Html::new_fragment();
+        html.tree
+            .root_mut()
+            .append_subtree(table_of_contents(&outline));
+
+        // Deeper entries go in a list below the previous entry, and shallower
+        // ones than the first stay at the top
+        assert_eq!(
+            html.root_element().html(),
+            concat!(
+                r#"<nav class="table-of-contents"><ol>"#,
+                r##"<li><a href="#a">A</a><ol><li><a href="#a-1">A.1</a><ol><li><a href="#a-1-1">A.1.1</a></li></ol></li></ol></li>"##,
+                r##"<li><a href="#b">B</a><ol><li><a href="#b-1">B.1</a></li><li><a href="#b-2">B.2</a></li></ol></li>"##,
+                r##"<li><a href="#c">C</a></li>"##,
+                "</ol></nav>",
+            )
+        );
     }
 }
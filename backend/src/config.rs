@@ -1,29 +1,256 @@
-use std::{fs, io, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Parser, Subcommand};
 use directories::ProjectDirs;
-use serde_derive::Deserialize;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde_derive::{Deserialize, Serialize};
 use thiserror::Error;
+use time::UtcDateTime;
 use uuid::Uuid;
 
+use crate::{
+    app::{DEFAULT_EDITOR_ADDRESS, DEFAULT_HTTP_ADDRESS},
+    graph_export::GraphFormat,
+};
+
 #[derive(Debug, Parser)]
 #[command(version, about)]
 pub struct Arguments {
     #[command(subcommand)]
     pub command: Commands,
+    /// Add a key-value pair visible to notes through `sys.inputs`, overriding
+    /// the inputs from config.toml
+    #[arg(
+        long = "input",
+        value_name = "KEY=VALUE",
+        value_parser = parse_input,
+        global = true
+    )]
+    pub inputs: Vec<(String, String)>,
+    /// The build date as a UNIX timestamp, used by `datetime.today()`
+    #[arg(long, env = "SOURCE_DATE_EPOCH", global = true)]
+    pub creation_timestamp: Option<i64>,
+    /// Log more, twice for everything. `RUST_LOG` takes precedence
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Also write logs to this file as JSON lines
+    #[arg(long, value_name = "PATH", global = true)]
+    pub log_file: Option<PathBuf>,
+    /// Read the config from this file instead of the nearest phelps.toml or
+    /// the user's config.toml
+    #[arg(long, value_name = "PATH", env = "PHELPS_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+}
+
+fn parse_input(raw: &str) -> Result<(String, String), String> {
+    let (key, value) = raw
+        .split_once('=')
+        .ok_or("input must be a key and a value separated by an equal sign")?;
+
+    if key.trim().is_empty() {
+        return Err("input key must not be empty".into());
+    }
+
+    Ok((key.trim().into(), value.into()))
 }
 
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     Watch,
+    /// List the font families available to notes
+    Fonts {
+        /// Also list the style, weight and stretch of each font
+        #[arg(long)]
+        variants: bool,
+    },
+    /// Build the notes once and print their link graph
+    Graph {
+        #[arg(long, value_enum, default_value_t)]
+        format: GraphFormat,
+        /// Include the metadata of each note
+        #[arg(long)]
+        metadata: bool,
+        /// Include the dependency graph between source files
+        #[arg(long)]
+        files: bool,
+    },
+    /// Inspect the config
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the config after merging the config file, environment variables
+    /// and command line arguments
+    Show,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ConfigToml {
-    pub project_directory: PathBuf,
+    /// Required in the user's config.toml. In a phelps.toml it's relative to
+    /// the directory of the file, which is also the default.
+    #[serde(default)]
+    pub project_directory: Option<PathBuf>,
     pub default_note: Uuid,
     #[serde(default)]
     pub extra_directories: Vec<PathBuf>,
+    #[serde(default)]
+    pub cache_directory: Option<PathBuf>,
+    #[serde(default)]
+    pub data_directory: Option<PathBuf>,
+    /// Where the HTTP API and the frontend are served.
+    #[serde(default)]
+    pub http_address: Option<SocketAddr>,
+    /// Where editors connect to preview unsaved changes.
+    #[serde(default)]
+    pub editor_address: Option<SocketAddr>,
+    #[serde(default)]
+    pub fonts: FontConfig,
+    #[serde(default)]
+    pub creation_timestamp: Option<i64>,
+    #[serde(default)]
+    pub inputs: BTreeMap<String, String>,
+    #[serde(default)]
+    pub notes: NoteConfig,
+    #[serde(default)]
+    pub sources: SourceConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct FontConfig {
+    /// Extra directories to search for fonts, relative to the project
+    /// directory. These take priority over system and embedded fonts.
+    pub directories: Vec<PathBuf>,
+    /// Whether to load the fonts installed on this machine.
+    pub system: bool,
+    /// Whether to load the fonts embedded in Typst itself.
+    pub embedded: bool,
+}
+
+impl Default for FontConfig {
+    fn default() -> Self {
+        Self {
+            directories: Vec::new(),
+            system: true,
+            embedded: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubNotes {
+    /// Sub-notes stay part of their parent's content.
+    #[default]
+    Embed,
+    /// Sub-notes are replaced by a link in their parent's content.
+    Link,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct NoteConfig {
+    /// The heading level of top-level notes. A file can override this with
+    /// `#metadata(2) <note-level>`. Labelled headings below this level are
+    /// sub-notes of the note they appear in.
+    pub level: usize,
+    pub sub_notes: SubNotes,
+    /// Whether to put a table of contents at the top of each note.
+    pub table_of_contents: bool,
+}
+
+impl Default for NoteConfig {
+    fn default() -> Self {
+        Self {
+            level: 1,
+            sub_notes: SubNotes::default(),
+            table_of_contents: false,
+        }
+    }
+}
+
+/// Which files in the source directories are notes. Globs are relative to the
+/// project directory, and files ignored by `.gitignore` or `.ignore` files are
+/// never notes.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct SourceConfig {
+    /// If not empty, only files matching one of these globs are notes.
+    pub include: Vec<String>,
+    /// Files matching any of these globs aren't notes.
+    pub exclude: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SourceGlobs {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    /// The globs the sets were built from, to tell whether they changed.
+    config: SourceConfig,
+}
+
+impl PartialEq for SourceGlobs {
+    fn eq(&self, other: &Self) -> bool {
+        self.config == other.config
+    }
+}
+
+impl SourceGlobs {
+    pub fn try_build(config: SourceConfig) -> Result<Self, ConfigError> {
+        fn glob_set(globs: Vec<String>) -> Result<GlobSet, ConfigError> {
+            let mut builder = GlobSetBuilder::new();
+            let patterns = globs.clone();
+
+            for glob in globs {
+                let glob = GlobBuilder::new(&glob)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|error| ConfigError::InvalidGlob(glob, error))?;
+                builder.add(glob);
+            }
+
+            builder.build().map_err(|error| {
+                let glob = error
+                    .glob()
+                    .map(str::to_owned)
+                    .unwrap_or_else(|| patterns.join(", "));
+
+                ConfigError::InvalidGlob(glob, error)
+            })
+        }
+
+        let include = if config.include.is_empty() {
+            None
+        } else {
+            Some(glob_set(config.include.clone())?)
+        };
+        let exclude = glob_set(config.exclude.clone())?;
+
+        Ok(Self {
+            include,
+            exclude,
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &SourceConfig {
+        &self.config
+    }
+
+    /// Whether a path relative to the project directory is included and not
+    /// excluded.
+    pub fn is_match(&self, path: &Path) -> bool {
+        self.include.as_ref().is_none_or(|set| set.is_match(path)) && !self.exclude.is_match(path)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -34,53 +261,267 @@ pub struct Config {
     pub notes_subdirectory: PathBuf,
     pub extra_directories: Vec<PathBuf>,
     pub build_subdirectory: PathBuf,
+    pub http_address: SocketAddr,
+    pub editor_address: SocketAddr,
     pub default_note: Uuid,
+    pub fonts: FontConfig,
+    pub inputs: BTreeMap<String, String>,
+    pub creation_timestamp: Option<UtcDateTime>,
+    pub notes: NoteConfig,
+    pub sources: SourceGlobs,
+    /// The file the config was read from, which is watched for changes while
+    /// phelps runs.
+    pub path: Option<PathBuf>,
+    pub overrides: ConfigOverrides,
+}
+
+/// Settings from the environment and the command line, which take precedence
+/// over the config file and are applied again whenever it's reloaded.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigOverrides {
+    /// `PHELPS_*` variables, with the prefix removed.
+    pub environment: Vec<(String, String)>,
+    pub inputs: Vec<(String, String)>,
+    pub creation_timestamp: Option<i64>,
+    /// Used instead of the config file's project directory, to resolve the
+    /// other paths the way the running services did.
+    pub project_directory: Option<PathBuf>,
+}
+
+/// Prefix of environment variables overriding config file settings, such as
+/// `PHELPS_DEFAULT_NOTE` or `PHELPS_NOTES_LEVEL`.
+const ENVIRONMENT_PREFIX: &str = "PHELPS_";
+/// Settings at the top of the config file which variables can override.
+const ENVIRONMENT_SETTINGS: [&str; 8] = [
+    "project_directory",
+    "default_note",
+    "extra_directories",
+    "cache_directory",
+    "data_directory",
+    "http_address",
+    "editor_address",
+    "creation_timestamp",
+];
+/// Tables in the config file, whose settings are overridden by variables
+/// like `PHELPS_<TABLE>_<KEY>`.
+const ENVIRONMENT_TABLES: [&str; 4] = ["fonts", "inputs", "notes", "sources"];
+/// Name of the per-vault config file, looked for in the current directory and
+/// its ancestors.
+pub const VAULT_CONFIG: &str = "phelps.toml";
+
+impl From<&Arguments> for ConfigOverrides {
+    fn from(arguments: &Arguments) -> Self {
+        let environment = env::vars()
+            .filter_map(|(name, value)| {
+                let name = name.strip_prefix(ENVIRONMENT_PREFIX)?;
+
+                // Picks the config file rather than overriding a setting
+                (name != "CONFIG").then(|| (name.to_owned(), value))
+            })
+            .collect();
+
+        Self {
+            environment,
+            inputs: arguments.inputs.clone(),
+            creation_timestamp: arguments.creation_timestamp,
+            project_directory: None,
+        }
+    }
+}
+
+impl ConfigOverrides {
+    /// Sets the settings named by environment variables in a parsed config
+    /// file. Values are read as TOML where possible, and as strings otherwise.
+    /// Inputs are always strings. Variables which don't name a setting are an
+    /// error, so that typos don't go unnoticed.
+    fn apply_environment(&self, table: &mut toml::Table) -> Result<(), ConfigError> {
+        for (name, raw) in &self.environment {
+            let key = name.to_lowercase();
+            let unknown = || ConfigError::UnknownEnvironmentVariable(name.clone());
+            let value = toml::from_str::<toml::Table>(&format!("value = {raw}"))
+                .ok()
+                .and_then(|mut parsed| parsed.remove("value"))
+                .unwrap_or_else(|| toml::Value::String(raw.clone()));
+
+            let nested = ENVIRONMENT_TABLES.iter().find_map(|table| {
+                key.strip_prefix(table)
+                    .and_then(|rest| rest.strip_prefix('_'))
+                    .filter(|rest| !rest.is_empty())
+                    .map(|rest| (*table, rest))
+            });
+            match nested {
+                Some((name, key)) => {
+                    let value = match name {
+                        "inputs" => toml::Value::String(raw.clone()),
+                        _ if table_settings(name).contains(&key.to_owned()) => value,
+                        _ => return Err(unknown()),
+                    };
+                    let entry = table
+                        .entry(name)
+                        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+
+                    if let toml::Value::Table(entry) = entry {
+                        entry.insert(key.to_owned(), value);
+                    }
+                }
+                None if ENVIRONMENT_SETTINGS.contains(&key.as_str()) => {
+                    table.insert(key, value);
+                }
+                None => return Err(unknown()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The settings of a table in the config file, taken from its defaults.
+fn table_settings(name: &str) -> Vec<String> {
+    let table = match name {
+        "fonts" => toml::Table::try_from(FontConfig::default()),
+        "notes" => toml::Table::try_from(NoteConfig::default()),
+        "sources" => toml::Table::try_from(SourceConfig::default()),
+        _ => return Vec::new(),
+    };
+
+    table
+        .map(|table| table.into_iter().map(|(key, _)| key).collect())
+        .unwrap_or_default()
 }
 
 #[derive(Debug, Error)]
 pub enum ConfigError {
     #[error("home directory missing, cannot determine project directories")]
     MissingHomeDirectory,
-    #[error("couldn't read config.toml file")]
-    ConfigRead(io::Error),
-    #[error("couldn't parse config.toml file")]
-    ConfigParse(toml::de::Error),
+    #[error("couldn't read config file {0}: {1}")]
+    ConfigRead(PathBuf, io::Error),
+    #[error("couldn't parse config file {0}: {1}")]
+    ConfigParse(PathBuf, toml::de::Error),
     #[error("project directory does not exist")]
     MissingProjectDirectory,
     #[error("notes subdirectory does not exist")]
     MissingNotesSubdirectory,
     #[error("extra directory does not exist: {0}")]
     MissingExtraDirectory(PathBuf),
+    #[error("font directory does not exist: {0}")]
+    MissingFontDirectory(PathBuf),
+    #[error("creation timestamp out of range: {0}")]
+    InvalidCreationTimestamp(i64),
+    #[error("note level must be at least 1")]
+    InvalidNoteLevel,
+    #[error("invalid source glob {0:?}: {1}")]
+    InvalidGlob(String, globset::Error),
+    #[error("PHELPS_{0} doesn't name a setting")]
+    UnknownEnvironmentVariable(String),
 }
 
 impl Config {
-    pub fn try_build() -> Result<Self, ConfigError> {
+    pub fn try_build(arguments: &Arguments) -> Result<Self, ConfigError> {
+        Self::read(Self::find(arguments)?, arguments.into())
+    }
+
+    /// The config file to read: the one given on the command line, else the
+    /// nearest `phelps.toml`, else the user's `config.toml`.
+    pub fn find(arguments: &Arguments) -> Result<PathBuf, ConfigError> {
+        let current_directory = env::current_dir()
+            .map_err(|error| ConfigError::ConfigRead(PathBuf::from("."), error))?;
+
+        Self::find_from(arguments.config.as_deref(), &current_directory)
+    }
+
+    fn find_from(config: Option<&Path>, current_directory: &Path) -> Result<PathBuf, ConfigError> {
+        if let Some(path) = config {
+            return fs::canonicalize(current_directory.join(path))
+                .map_err(|error| ConfigError::ConfigRead(path.to_owned(), error));
+        }
+
+        if let Some(path) = current_directory
+            .ancestors()
+            .map(|directory| directory.join(VAULT_CONFIG))
+            .find(|path| path.is_file())
+        {
+            return Ok(path);
+        }
+
         let project_directories =
             ProjectDirs::from("", "", "phelps").ok_or(ConfigError::MissingHomeDirectory)?;
 
-        let data_directory = project_directories.data_dir().to_owned();
-        let cache_directory = project_directories.data_dir().to_owned();
+        Ok(project_directories.config_dir().join("config.toml"))
+    }
+
+    pub fn read(path: PathBuf, overrides: ConfigOverrides) -> Result<Self, ConfigError> {
+        let project_directories =
+            ProjectDirs::from("", "", "phelps").ok_or(ConfigError::MissingHomeDirectory)?;
 
-        let config_path: PathBuf = project_directories.config_dir().join("config.toml");
-        let contents = fs::read_to_string(&config_path).map_err(ConfigError::ConfigRead)?;
+        let contents = fs::read_to_string(&path)
+            .map_err(|error| ConfigError::ConfigRead(path.clone(), error))?;
+        let mut table: toml::Table = toml::from_str(&contents)
+            .map_err(|error| ConfigError::ConfigParse(path.clone(), error))?;
+        overrides.apply_environment(&mut table)?;
         let ConfigToml {
             project_directory,
             default_note,
             extra_directories,
-        } = toml::from_str(&contents).map_err(ConfigError::ConfigParse)?;
+            cache_directory,
+            data_directory,
+            http_address,
+            editor_address,
+            mut fonts,
+            mut inputs,
+            creation_timestamp,
+            notes,
+            sources,
+        } = table
+            .try_into()
+            .map_err(|error| ConfigError::ConfigParse(path.clone(), error))?;
 
+        // A phelps.toml lives in its vault, the user's config.toml doesn't
+        let config_directory = path
+            .parent()
+            .filter(|_| path.file_name().is_some_and(|name| name == VAULT_CONFIG));
+        let project_directory = match (&overrides.project_directory, project_directory) {
+            (Some(directory), _) => directory.clone(),
+            (None, Some(directory)) => match config_directory {
+                Some(config_directory) => config_directory.join(directory),
+                None => directory,
+            },
+            (None, None) => match config_directory {
+                Some(config_directory) => config_directory.to_owned(),
+                None => {
+                    return Err(ConfigError::ConfigParse(
+                        path,
+                        serde::de::Error::missing_field("project_directory"),
+                    ));
+                }
+            },
+        };
         let notes_subdirectory = project_directory.join("notes");
         let build_subdirectory = project_directory.join("build");
-        let extra_directories: Vec<PathBuf> = extra_directories
-            .into_iter()
-            .map(|dir| {
-                if dir.is_absolute() {
-                    dir
-                } else {
-                    project_directory.join(dir)
-                }
+        let resolve = |dir: PathBuf| {
+            if dir.is_absolute() {
+                dir
+            } else {
+                project_directory.join(dir)
+            }
+        };
+        let extra_directories: Vec<PathBuf> = extra_directories.into_iter().map(resolve).collect();
+        let data_directory = data_directory
+            .map(resolve)
+            .unwrap_or_else(|| project_directories.data_dir().to_owned());
+        let cache_directory = cache_directory
+            .map(resolve)
+            .unwrap_or_else(|| project_directories.cache_dir().to_owned());
+        fonts.directories = fonts.directories.into_iter().map(resolve).collect();
+        inputs.extend(overrides.inputs.iter().cloned());
+        let creation_timestamp = overrides
+            .creation_timestamp
+            .or(creation_timestamp)
+            .map(|timestamp| {
+                UtcDateTime::from_unix_timestamp(timestamp)
+                    .map_err(|_| ConfigError::InvalidCreationTimestamp(timestamp))
             })
-            .collect();
+            .transpose()?;
+        let sources = SourceGlobs::try_build(sources)?;
 
         if !project_directory.exists() {
             return Err(ConfigError::MissingProjectDirectory);
@@ -93,6 +534,14 @@ impl Config {
                 return Err(ConfigError::MissingExtraDirectory(directory.clone()));
             }
         }
+        if notes.level == 0 {
+            return Err(ConfigError::InvalidNoteLevel);
+        }
+        for directory in &fonts.directories {
+            if !directory.exists() {
+                return Err(ConfigError::MissingFontDirectory(directory.clone()));
+            }
+        }
 
         Ok(Config {
             data_directory,
@@ -101,7 +550,189 @@ impl Config {
             notes_subdirectory,
             extra_directories,
             build_subdirectory,
+            http_address: http_address.unwrap_or(DEFAULT_HTTP_ADDRESS),
+            editor_address: editor_address.unwrap_or(DEFAULT_EDITOR_ADDRESS),
             default_note,
+            fonts,
+            inputs,
+            creation_timestamp,
+            notes,
+            sources,
+            path: Some(path),
+            overrides,
         })
     }
+
+    /// The settings as they'd be written in a config file, with paths
+    /// resolved and overrides applied.
+    pub fn to_toml(&self) -> ConfigToml {
+        ConfigToml {
+            project_directory: Some(self.project_directory.clone()),
+            default_note: self.default_note,
+            extra_directories: self.extra_directories.clone(),
+            cache_directory: Some(self.cache_directory.clone()),
+            data_directory: Some(self.data_directory.clone()),
+            http_address: Some(self.http_address),
+            editor_address: Some(self.editor_address),
+            fonts: self.fonts.clone(),
+            creation_timestamp: self
+                .creation_timestamp
+                .map(|timestamp| timestamp.unix_timestamp()),
+            inputs: self.inputs.clone(),
+            notes: self.notes.clone(),
+            sources: self.sources.config().clone(),
+        }
+    }
+
+    /// The directories searched for notes: the notes subdirectory followed by
+    /// the extra directories.
+    pub fn source_directories(&self) -> Vec<PathBuf> {
+        let mut directories = Vec::with_capacity(1 + self.extra_directories.len());
+        directories.push(self.notes_subdirectory.clone());
+        directories.extend(self.extra_directories.iter().cloned());
+
+        directories
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn overrides(environment: &[(&str, &str)]) -> ConfigOverrides {
+        ConfigOverrides {
+            environment: environment
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..ConfigOverrides::default()
+        }
+    }
+
+    fn apply(file: &str, environment: &[(&str, &str)]) -> Result<toml::Table, ConfigError> {
+        let mut table: toml::Table = toml::from_str(file).unwrap();
+        overrides(environment).apply_environment(&mut table)?;
+
+        Ok(table)
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let table = apply(
+            "default_note = \"00000000-0000-0000-0000-000000000001\"\n[notes]\nlevel = 1\n",
+            &[
+                ("DEFAULT_NOTE", "00000000-0000-0000-0000-000000000002"),
+                ("NOTES_LEVEL", "2"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            table["default_note"].as_str(),
+            Some("00000000-0000-0000-0000-000000000002")
+        );
+        assert_eq!(table["notes"]["level"].as_integer(), Some(2));
+    }
+
+    #[test]
+    fn environment_values_are_toml_or_strings() {
+        let table = apply(
+            "",
+            &[
+                ("FONTS_SYSTEM", "false"),
+                ("SOURCES_EXCLUDE", "[\"drafts/**\"]"),
+                ("CACHE_DIRECTORY", "/tmp/cache"),
+                ("INPUTS_VERSION", "2"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(table["fonts"]["system"].as_bool(), Some(false));
+        assert_eq!(
+            table["sources"]["exclude"].as_array().unwrap()[0].as_str(),
+            Some("drafts/**")
+        );
+        assert_eq!(table["cache_directory"].as_str(), Some("/tmp/cache"));
+        // Inputs are strings even when they look like numbers
+        assert_eq!(table["inputs"]["version"].as_str(), Some("2"));
+    }
+
+    #[test]
+    fn unknown_environment_variables_are_rejected() {
+        for name in ["DEFAULT_NOT", "NOTES_DEPTH", "FONTS", "NOTES_"] {
+            assert!(
+                matches!(
+                    apply("", &[(name, "1")]),
+                    Err(ConfigError::UnknownEnvironmentVariable(unknown)) if unknown == name
+                ),
+                "{name} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn only_vault_configs_default_the_project_directory() {
+        let directory = TempDir::new().unwrap();
+        fs::create_dir(directory.path().join("notes")).unwrap();
+        let contents = "default_note = \"00000000-0000-0000-0000-000000000001\"\n";
+
+        let vault_config = directory.path().join(VAULT_CONFIG);
+        fs::write(&vault_config, contents).unwrap();
+        let config = Config::read(vault_config, ConfigOverrides::default()).unwrap();
+        assert_eq!(config.project_directory, directory.path());
+
+        let user_config = directory.path().join("config.toml");
+        fs::write(&user_config, contents).unwrap();
+        assert!(matches!(
+            Config::read(user_config, ConfigOverrides::default()),
+            Err(ConfigError::ConfigParse(..))
+        ));
+    }
+
+    #[test]
+    fn find_prefers_the_flag() {
+        let directory = TempDir::new().unwrap();
+        fs::write(directory.path().join(VAULT_CONFIG), "").unwrap();
+        fs::write(directory.path().join("other.toml"), "").unwrap();
+
+        let found = Config::find_from(Some(Path::new("other.toml")), directory.path()).unwrap();
+
+        assert_eq!(
+            found,
+            fs::canonicalize(directory.path().join("other.toml")).unwrap()
+        );
+        assert!(matches!(
+            Config::find_from(Some(Path::new("missing.toml")), directory.path()),
+            Err(ConfigError::ConfigRead(..))
+        ));
+    }
+
+    #[test]
+    fn find_looks_in_ancestors() {
+        let directory = TempDir::new().unwrap();
+        let nested = directory.path().join("notes/drafts");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(directory.path().join(VAULT_CONFIG), "").unwrap();
+
+        assert_eq!(
+            Config::find_from(None, &nested).unwrap(),
+            directory.path().join(VAULT_CONFIG)
+        );
+    }
+
+    #[test]
+    fn find_falls_back_to_the_user_config() {
+        let directory = TempDir::new().unwrap();
+        let user_config = ProjectDirs::from("", "", "phelps")
+            .unwrap()
+            .config_dir()
+            .join("config.toml");
+
+        assert_eq!(
+            Config::find_from(None, directory.path()).unwrap(),
+            user_config
+        );
+    }
 }
@@ -0,0 +1,194 @@
+use std::{path::PathBuf, time::Duration};
+
+use notify_debouncer_full::{
+    DebounceEventResult, Debouncer, RecommendedCache, new_debouncer,
+    notify::{self, EventKind, RecommendedWatcher},
+};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    build_service::{BuildServiceHandle, MpscWrapper, Reconfiguration},
+    config::{Config, ConfigOverrides},
+    notes_service::NotesServiceHandle,
+    system_world::Resources,
+};
+
+/// Watches the config file and applies changes to it while phelps runs.
+/// Settings which can't change without starting over are reported instead.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    /// The config the services are running with.
+    config: Config,
+    notes_service: NotesServiceHandle,
+    build_service: BuildServiceHandle,
+    receiver: mpsc::Receiver<DebounceEventResult>,
+    watcher: Debouncer<RecommendedWatcher, RecommendedCache>,
+    cancel: CancellationToken,
+}
+
+impl ConfigWatcher {
+    /// Returns `None` if the config wasn't read from a file.
+    pub fn try_build(
+        config: Config,
+        notes_service: NotesServiceHandle,
+        build_service: BuildServiceHandle,
+        cancel: CancellationToken,
+    ) -> Result<Option<Self>, notify::Error> {
+        const BUFFER_SIZE: usize = 16;
+        const DEBOUNCE_TIMEOUT: Duration = Duration::from_millis(500);
+
+        let Some(path) = config.path.clone() else {
+            return Ok(None);
+        };
+        let (sender, receiver) = mpsc::channel(BUFFER_SIZE);
+        let watcher = new_debouncer(DEBOUNCE_TIMEOUT, None, MpscWrapper(sender))?;
+
+        Ok(Some(Self {
+            path,
+            config,
+            notes_service,
+            build_service,
+            receiver,
+            watcher,
+            cancel,
+        }))
+    }
+
+    pub async fn run(mut self) {
+        // Editors often save by replacing the file, which would end a watch
+        // on the file itself
+        let Some(directory) = self.path.parent() else {
+            return;
+        };
+        if let Err(error) = self
+            .watcher
+            .watch(directory, notify::RecursiveMode::NonRecursive)
+        {
+            warn!(%error, path = %self.path.display(), "couldn't watch the config file, changes need a restart");
+
+            return;
+        }
+
+        loop {
+            tokio::select! {
+                option = self.receiver.recv() => match option {
+                    Some(Ok(events)) => {
+                        let changed = events.iter().any(|event| {
+                            !matches!(event.kind, EventKind::Access(_))
+                                && event.paths.contains(&self.path)
+                        });
+
+                        if changed {
+                            self.reload().await;
+                        }
+                    }
+                    Some(Err(_)) => (),
+                    None => break,
+                },
+                _ = self.cancel.cancelled() => break,
+            }
+        }
+    }
+
+    async fn reload(&mut self) {
+        let mut config = match Config::read(self.path.clone(), self.config.overrides.clone()) {
+            Ok(config) => config,
+            Err(error) => {
+                warn!(%error, path = %self.path.display(), "invalid config, keeping the current one");
+
+                return;
+            }
+        };
+        let current = &self.config;
+
+        // These are baked into the services and listeners, so keep running
+        // with the old values until a restart
+        let restart: Vec<&str> = [
+            (
+                "project_directory",
+                config.project_directory != current.project_directory,
+            ),
+            (
+                "cache_directory",
+                config.cache_directory != current.cache_directory,
+            ),
+            (
+                "data_directory",
+                config.data_directory != current.data_directory,
+            ),
+            ("http_address", config.http_address != current.http_address),
+            (
+                "editor_address",
+                config.editor_address != current.editor_address,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(setting, changed)| changed.then_some(setting))
+        .collect();
+        if !restart.is_empty() {
+            warn!(
+                settings = restart.join(", "),
+                "config changes need a restart to take effect"
+            );
+
+            if config.project_directory != current.project_directory {
+                // Other paths are relative to the project directory, so they
+                // need resolving against the one the services run in
+                let overrides = ConfigOverrides {
+                    project_directory: Some(current.project_directory.clone()),
+                    ..current.overrides.clone()
+                };
+                config = match Config::read(self.path.clone(), overrides) {
+                    Ok(config) => config,
+                    Err(error) => {
+                        warn!(%error, path = %self.path.display(), "invalid config, keeping the current one");
+
+                        return;
+                    }
+                };
+                // Otherwise the next change wouldn't be compared against the
+                // file's project directory
+                config.overrides = current.overrides.clone();
+            }
+            config.cache_directory = current.cache_directory.clone();
+            config.data_directory = current.data_directory.clone();
+            config.http_address = current.http_address;
+            config.editor_address = current.editor_address;
+        }
+
+        if config.default_note != current.default_note {
+            let _ = self
+                .notes_service
+                .set_default_note(config.default_note)
+                .await;
+        }
+
+        let mut reconfiguration = Reconfiguration::default();
+        let source_directories = config.source_directories();
+        if source_directories != current.source_directories() || config.sources != current.sources {
+            reconfiguration.sources = Some((source_directories, config.sources.clone()));
+        }
+        if config.notes != current.notes {
+            reconfiguration.note_config = Some(config.notes.clone());
+        }
+        if config.fonts != current.fonts
+            || config.inputs != current.inputs
+            || config.creation_timestamp != current.creation_timestamp
+        {
+            let resources = config.clone();
+            reconfiguration.resources = Some(
+                tokio::task::spawn_blocking(move || Resources::from_config(&resources))
+                    .await
+                    .unwrap(),
+            );
+        }
+        if !reconfiguration.is_empty() {
+            let _ = self.build_service.reconfigure(reconfiguration).await;
+        }
+
+        info!(path = %self.path.display(), "reloaded config");
+        self.config = config;
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+
+use petgraph::{Direction, prelude::DiGraphMap, visit::Bfs};
+use typst::syntax::FileId;
+
+use crate::graph_export::{Edge, FileGraph};
+
+/// The files each main file read during its latest compile, whether through
+/// imports and includes or through `World::file` for images, bibliographies
+/// and data. Edges go from a file to the files depending on it, so everything
+/// affected by a change is reachable from the changed file.
+#[derive(Debug, Default)]
+pub struct DependencyGraph {
+    graph: DiGraphMap<FileId, ()>,
+    /// Files which are compiled on their own.
+    mains: HashSet<FileId>,
+}
+
+impl DependencyGraph {
+    /// Replaces the files a main file depends on.
+    pub fn set_dependencies(
+        &mut self,
+        main: FileId,
+        dependencies: impl IntoIterator<Item = FileId>,
+    ) {
+        self.mains.insert(main);
+        self.graph.add_node(main);
+
+        let previous = self.unlink(main);
+        for dependency in dependencies {
+            if dependency != main {
+                self.graph.add_edge(dependency, main, ());
+            }
+        }
+        for file in previous {
+            self.prune(file);
+        }
+    }
+
+    /// Forgets a main file. It stays in the graph as long as other files still
+    /// depend on it.
+    pub fn remove(&mut self, main: FileId) {
+        if !self.mains.remove(&main) {
+            return;
+        }
+
+        let previous = self.unlink(main);
+        self.prune(main);
+        for file in previous {
+            self.prune(file);
+        }
+    }
+
+    pub fn contains(&self, file: FileId) -> bool {
+        self.graph.contains_node(file)
+    }
+
+    pub fn is_main(&self, file: FileId) -> bool {
+        self.mains.contains(&file)
+    }
+
+    pub fn mains(&self) -> impl Iterator<Item = FileId> {
+        self.mains.iter().copied()
+    }
+
+    /// The file itself followed by every file depending on it, directly or
+    /// not.
+    pub fn affected(&self, file: FileId) -> Vec<FileId> {
+        if !self.graph.contains_node(file) {
+            return vec![file];
+        }
+
+        let mut bfs = Bfs::new(&self.graph, file);
+        let mut files = Vec::new();
+        while let Some(j) = bfs.next(&self.graph) {
+            files.push(j);
+        }
+
+        files
+    }
+
+    /// Exports the graph with edges going from a file to the files it depends
+    /// on, which reads more naturally.
+    pub fn export(&self, name: impl Fn(FileId) -> String) -> FileGraph {
+        let mut files: Vec<String> = self.graph.nodes().map(&name).collect();
+        files.sort();
+        let mut dependencies: Vec<Edge<String>> = self
+            .graph
+            .all_edges()
+            .map(|(dependency, dependent, _)| Edge {
+                source: name(dependent),
+                target: name(dependency),
+            })
+            .collect();
+        dependencies.sort_by(|u, v| (&u.source, &u.target).cmp(&(&v.source, &v.target)));
+
+        FileGraph {
+            files,
+            dependencies,
+        }
+    }
+
+    /// Removes the edges to a file from its dependencies, returning them.
+    fn unlink(&mut self, file: FileId) -> Vec<FileId> {
+        let dependencies: Vec<FileId> = self
+            .graph
+            .neighbors_directed(file, Direction::Incoming)
+            .collect();
+        for &dependency in &dependencies {
+            self.graph.remove_edge(dependency, file);
+        }
+
+        dependencies
+    }
+
+    /// Drops a file which is neither compiled nor read by any compiled file.
+    fn prune(&mut self, file: FileId) {
+        if !self.mains.contains(&file)
+            && self
+                .graph
+                .neighbors_directed(file, Direction::Outgoing)
+                .next()
+                .is_none()
+        {
+            self.graph.remove_node(file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use typst::syntax::VirtualPath;
+
+    use super::*;
+
+    fn file(path: &str) -> FileId {
+        FileId::new(None, VirtualPath::new(path))
+    }
+
+    fn sorted(mut files: Vec<FileId>) -> Vec<FileId> {
+        files.sort_by_key(|id| id.into_raw());
+        files
+    }
+
+    #[test]
+    fn changes_reach_dependents() {
+        let mut graph = DependencyGraph::default();
+        let (a, b, template, image) = (
+            file("notes/a.typ"),
+            file("notes/b.typ"),
+            file("template.typ"),
+            file("images/cat.png"),
+        );
+
+        graph.set_dependencies(a, [template, image]);
+        graph.set_dependencies(b, [template]);
+
+        assert_eq!(
+            sorted(graph.affected(template)),
+            sorted(vec![a, b, template])
+        );
+        assert_eq!(sorted(graph.affected(image)), sorted(vec![a, image]));
+        assert_eq!(graph.affected(a), vec![a]);
+        assert!(graph.is_main(a) && !graph.is_main(image));
+    }
+
+    #[test]
+    fn create_modify_delete() {
+        let mut graph = DependencyGraph::default();
+        let (a, data, bibliography) = (
+            file("notes/a.typ"),
+            file("data.csv"),
+            file("references.bib"),
+        );
+
+        // Created, reading a data file
+        graph.set_dependencies(a, [data]);
+        assert_eq!(sorted(graph.affected(data)), sorted(vec![a, data]));
+
+        // Modified to read a bibliography instead, the data file is forgotten
+        graph.set_dependencies(a, [bibliography]);
+        assert!(!graph.contains(data));
+        assert_eq!(graph.affected(data), vec![data]);
+        assert_eq!(
+            sorted(graph.affected(bibliography)),
+            sorted(vec![a, bibliography])
+        );
+
+        // Deleted, nothing is left
+        graph.remove(a);
+        assert!(!graph.contains(a));
+        assert!(!graph.contains(bibliography));
+        assert!(graph.export(|id| format!("{id:?}")).files.is_empty());
+    }
+
+    #[test]
+    fn removed_main_stays_while_imported() {
+        let mut graph = DependencyGraph::default();
+        let (a, b) = (file("notes/a.typ"), file("notes/b.typ"));
+
+        graph.set_dependencies(a, []);
+        graph.set_dependencies(b, [a]);
+        graph.remove(a);
+
+        // b still imports a, so a change to a must rebuild b
+        assert!(graph.contains(a) && !graph.is_main(a));
+        assert_eq!(sorted(graph.affected(a)), sorted(vec![a, b]));
+
+        // Recreated
+        graph.set_dependencies(a, []);
+        assert!(graph.is_main(a));
+        assert_eq!(sorted(graph.affected(a)), sorted(vec![a, b]));
+    }
+
+    #[test]
+    fn export_points_at_dependencies() {
+        let mut graph = DependencyGraph::default();
+        let (a, template) = (file("notes/a.typ"), file("template.typ"));
+
+        graph.set_dependencies(a, [template]);
+        let export = graph.export(|id| id.vpath().as_rootless_path().display().to_string());
+
+        assert_eq!(export.files, vec!["notes/a.typ", "template.typ"]);
+        assert_eq!(export.dependencies.len(), 1);
+        assert_eq!(export.dependencies[0].source, "notes/a.typ");
+        assert_eq!(export.dependencies[0].target, "template.typ");
+    }
+}
@@ -4,6 +4,7 @@ use std::{
     future::Future,
     io,
     net::SocketAddr,
+    path::PathBuf,
     pin::Pin,
     task::{Context, Poll},
 };
@@ -15,9 +16,13 @@ use tokio::{
 };
 use tokio_util::sync::CancellationToken;
 use tower::{MakeService, Service};
+use tracing::{Instrument, debug, info_span, warn};
 use uuid::Uuid;
 
-use crate::notes_service::NoteItem;
+use crate::{
+    notes_service::{GraphQuery, GraphQueryResult, NoteItem},
+    system_world::TextChange,
+};
 
 #[derive(Debug)]
 pub struct EditorServer<M> {
@@ -45,6 +50,28 @@ pub struct GetNotesResponse {
     pub items: Result<Vec<NoteItem>, String>,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct GetNotesByTagRequest {
+    /// The tag, which can't be called `tag` since that field names the
+    /// method.
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetNotesByTagResponse {
+    pub items: Result<Vec<NoteItem>, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GraphQueryRequest {
+    pub query: GraphQuery,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GraphQueryResponse {
+    pub result: Result<GraphQueryResult, String>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct FocusNoteRequest {
     pub id: Uuid,
@@ -55,18 +82,76 @@ pub struct FocusNoteResponse {
     pub result: Result<(), String>,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct DidChangeRequest {
+    pub path: PathBuf,
+    pub changes: Vec<TextChange>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DidChangeResponse {
+    pub result: Result<(), String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DidCloseRequest {
+    pub path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DidCloseResponse {
+    pub result: Result<(), String>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "tag")]
-pub enum Message<GetNotes, FocusNote> {
+pub enum Message<GetNotes, GetNotesByTag, GraphQuery, FocusNote, DidChange, DidClose> {
     #[serde(rename(serialize = "get_notes", deserialize = "get_notes"))]
     GetNotes(GetNotes),
+    #[serde(rename(serialize = "get_notes_by_tag", deserialize = "get_notes_by_tag"))]
+    GetNotesByTag(GetNotesByTag),
+    #[serde(rename(serialize = "graph_query", deserialize = "graph_query"))]
+    GraphQuery(GraphQuery),
     #[serde(rename(serialize = "focus_note", deserialize = "focus_note"))]
     FocusNote(FocusNote),
+    #[serde(rename(serialize = "did_change", deserialize = "did_change"))]
+    DidChange(DidChange),
+    #[serde(rename(serialize = "did_close", deserialize = "did_close"))]
+    DidClose(DidClose),
 }
 
-pub type Request = Message<GetNotesRequest, FocusNoteRequest>;
+impl<GetNotes, GetNotesByTag, GraphQuery, FocusNote, DidChange, DidClose>
+    Message<GetNotes, GetNotesByTag, GraphQuery, FocusNote, DidChange, DidClose>
+{
+    pub fn method(&self) -> &'static str {
+        match self {
+            Message::GetNotes(_) => "get_notes",
+            Message::GetNotesByTag(_) => "get_notes_by_tag",
+            Message::GraphQuery(_) => "graph_query",
+            Message::FocusNote(_) => "focus_note",
+            Message::DidChange(_) => "did_change",
+            Message::DidClose(_) => "did_close",
+        }
+    }
+}
 
-pub type Response = Message<GetNotesResponse, FocusNoteResponse>;
+pub type Request = Message<
+    GetNotesRequest,
+    GetNotesByTagRequest,
+    GraphQueryRequest,
+    FocusNoteRequest,
+    DidChangeRequest,
+    DidCloseRequest,
+>;
+
+pub type Response = Message<
+    GetNotesResponse,
+    GetNotesByTagResponse,
+    GraphQueryResponse,
+    FocusNoteResponse,
+    DidChangeResponse,
+    DidCloseResponse,
+>;
 
 impl<M> EditorServer<M>
 where
@@ -87,7 +172,9 @@ where
                     let (socket, address) = result?;
                     let Ok(service) = self.make_service.make_service(address).await;
 
-                    tokio::spawn(handle_socket(socket, service));
+                    let span = info_span!("editor_request", %address);
+
+                    tokio::spawn(handle_socket(socket, service).instrument(span));
                 }
                 _ = self.cancel.cancelled() => {
                     break Ok(());
@@ -104,12 +191,8 @@ where
 {
     if let Err(error) = handle_socket_helper(socket, service).await {
         match error {
-            EditorHandleError::Io(error) => {
-                println!("handle socket error: {:?}", error)
-            }
-            EditorHandleError::Serde(error) => {
-                println!("handle socket error: {:?}", error)
-            }
+            EditorHandleError::Io(error) => warn!(%error, "editor request failed"),
+            EditorHandleError::Serde(error) => warn!(%error, "invalid editor request"),
         }
     }
 }
@@ -132,6 +215,7 @@ where
         .await
         .map_err(EditorHandleError::Io)?;
     let request: Request = serde_json::from_str(&buffer).map_err(EditorHandleError::Serde)?;
+    debug!(method = request.method(), "editor request");
 
     let Ok(response) = service.call(request).await;
 
@@ -150,10 +234,30 @@ pub trait Editor {
 
     fn get_notes(&mut self) -> Self::GetNotesFuture;
 
+    type GetNotesByTagError: Error;
+    type GetNotesByTagFuture: Future<Output = Result<Vec<NoteItem>, Self::GetNotesByTagError>>;
+
+    fn get_notes_by_tag(&mut self, tag: String) -> Self::GetNotesByTagFuture;
+
+    type GraphQueryError: Error;
+    type GraphQueryFuture: Future<Output = Result<GraphQueryResult, Self::GraphQueryError>>;
+
+    fn graph_query(&mut self, query: GraphQuery) -> Self::GraphQueryFuture;
+
     type FocusNoteError: Error;
     type FocusNoteFuture: Future<Output = Result<(), Self::FocusNoteError>>;
 
     fn focus_note(&mut self, id: Uuid) -> Self::FocusNoteFuture;
+
+    type DidChangeError: Error;
+    type DidChangeFuture: Future<Output = Result<(), Self::DidChangeError>>;
+
+    fn did_change(&mut self, path: PathBuf, changes: Vec<TextChange>) -> Self::DidChangeFuture;
+
+    type DidCloseError: Error;
+    type DidCloseFuture: Future<Output = Result<(), Self::DidCloseError>>;
+
+    fn did_close(&mut self, path: PathBuf) -> Self::DidCloseFuture;
 }
 
 #[derive(Debug, Clone)]
@@ -162,8 +266,14 @@ pub struct EditorServiceWrapper<T>(pub T);
 impl<T: Editor> Service<Request> for EditorServiceWrapper<T> {
     type Response = Response;
     type Error = Infallible;
-    type Future =
-        EditorServiceResponseFuture<T::GetNotesFuture, T::FocusNoteFuture>;
+    type Future = EditorServiceResponseFuture<
+        T::GetNotesFuture,
+        T::GetNotesByTagFuture,
+        T::GraphQueryFuture,
+        T::FocusNoteFuture,
+        T::DidChangeFuture,
+        T::DidCloseFuture,
+    >;
 
     fn poll_ready(&mut self, _context: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
@@ -171,30 +281,81 @@ impl<T: Editor> Service<Request> for EditorServiceWrapper<T> {
 
     fn call(&mut self, request: Request) -> Self::Future {
         match request {
-            Message::GetNotes(GetNotesRequest) => EditorServiceResponseFuture::GetNotes(
-                self.0.get_notes(),
-            ),
+            Message::GetNotes(GetNotesRequest) => {
+                EditorServiceResponseFuture::GetNotes(self.0.get_notes())
+            }
+            Message::GetNotesByTag(GetNotesByTagRequest { name }) => {
+                EditorServiceResponseFuture::GetNotesByTag(self.0.get_notes_by_tag(name))
+            }
+            Message::GraphQuery(GraphQueryRequest { query }) => {
+                EditorServiceResponseFuture::GraphQuery(self.0.graph_query(query))
+            }
             Message::FocusNote(FocusNoteRequest { id }) => {
                 EditorServiceResponseFuture::FocusNote(self.0.focus_note(id))
             }
+            Message::DidChange(DidChangeRequest { path, changes }) => {
+                EditorServiceResponseFuture::DidChange(self.0.did_change(path, changes))
+            }
+            Message::DidClose(DidCloseRequest { path }) => {
+                EditorServiceResponseFuture::DidClose(self.0.did_close(path))
+            }
         }
     }
 }
 
 #[pin_project::pin_project(project = EditorServiceResponseFutureProjection)]
 #[derive(Debug)]
-pub enum EditorServiceResponseFuture<GetNotesFuture, FocusNoteFuture> {
+pub enum EditorServiceResponseFuture<
+    GetNotesFuture,
+    GetNotesByTagFuture,
+    GraphQueryFuture,
+    FocusNoteFuture,
+    DidChangeFuture,
+    DidCloseFuture,
+> {
     GetNotes(#[pin] GetNotesFuture),
+    GetNotesByTag(#[pin] GetNotesByTagFuture),
+    GraphQuery(#[pin] GraphQueryFuture),
     FocusNote(#[pin] FocusNoteFuture),
+    DidChange(#[pin] DidChangeFuture),
+    DidClose(#[pin] DidCloseFuture),
 }
 
-impl<GetNotesFuture, FocusNoteFuture, GetNotesError, FocusNoteError> Future
-    for EditorServiceResponseFuture<GetNotesFuture, FocusNoteFuture>
+impl<
+    GetNotesFuture,
+    GetNotesByTagFuture,
+    GraphQueryFuture,
+    FocusNoteFuture,
+    DidChangeFuture,
+    DidCloseFuture,
+    GetNotesError,
+    GetNotesByTagError,
+    GraphQueryError,
+    FocusNoteError,
+    DidChangeError,
+    DidCloseError,
+> Future
+    for EditorServiceResponseFuture<
+        GetNotesFuture,
+        GetNotesByTagFuture,
+        GraphQueryFuture,
+        FocusNoteFuture,
+        DidChangeFuture,
+        DidCloseFuture,
+    >
 where
     GetNotesFuture: Future<Output = Result<Vec<NoteItem>, GetNotesError>>,
     GetNotesError: Error,
+    GetNotesByTagFuture: Future<Output = Result<Vec<NoteItem>, GetNotesByTagError>>,
+    GetNotesByTagError: Error,
+    GraphQueryFuture: Future<Output = Result<GraphQueryResult, GraphQueryError>>,
+    GraphQueryError: Error,
     FocusNoteFuture: Future<Output = Result<(), FocusNoteError>>,
     FocusNoteError: Error,
+    DidChangeFuture: Future<Output = Result<(), DidChangeError>>,
+    DidChangeError: Error,
+    DidCloseFuture: Future<Output = Result<(), DidCloseError>>,
+    DidCloseError: Error,
 {
     type Output = Result<Response, Infallible>;
 
@@ -207,11 +368,31 @@ where
                     items: result.map_err(|error| format!("{:?}", error)),
                 }))
             }),
+            GetNotesByTag(future) => future.poll(context).map(|result| {
+                Ok(Response::GetNotesByTag(GetNotesByTagResponse {
+                    items: result.map_err(|error| format!("{:?}", error)),
+                }))
+            }),
+            GraphQuery(future) => future.poll(context).map(|result| {
+                Ok(Response::GraphQuery(GraphQueryResponse {
+                    result: result.map_err(|error| format!("{:?}", error)),
+                }))
+            }),
             FocusNote(future) => future.poll(context).map(|result| {
                 Ok(Response::FocusNote(FocusNoteResponse {
                     result: result.map_err(|error| format!("{:?}", error)),
                 }))
             }),
+            DidChange(future) => future.poll(context).map(|result| {
+                Ok(Response::DidChange(DidChangeResponse {
+                    result: result.map_err(|error| format!("{:?}", error)),
+                }))
+            }),
+            DidClose(future) => future.poll(context).map(|result| {
+                Ok(Response::DidClose(DidCloseResponse {
+                    result: result.map_err(|error| format!("{:?}", error)),
+                }))
+            }),
         }
     }
 }
@@ -1,21 +1,48 @@
-use std::pin::Pin;
+use std::{path::PathBuf, pin::Pin};
+
+use thiserror::Error;
 
 use crate::{
+    build_service::{BuildServiceHandle, BuildServiceHandleError},
     editor_protocol::Editor,
-    notes_service::{NoteItem, NotesServiceHandle, NotesServiceHandleError},
+    notes_service::{
+        GraphQuery, GraphQueryError, GraphQueryResult, NoteItem, NotesServiceHandle,
+        NotesServiceHandleError,
+    },
+    system_world::{OverlayError, TextChange},
 };
 
 #[derive(Clone, Debug)]
 pub struct EditorService {
     notes_service: NotesServiceHandle,
+    build_service: BuildServiceHandle,
 }
 
 impl EditorService {
-    pub fn new(notes_service: NotesServiceHandle) -> Self {
-        Self { notes_service }
+    pub fn new(notes_service: NotesServiceHandle, build_service: BuildServiceHandle) -> Self {
+        Self {
+            notes_service,
+            build_service,
+        }
     }
 }
 
+#[derive(Debug, Error)]
+pub enum BufferError {
+    #[error("build service error: {0}")]
+    BuildService(BuildServiceHandleError),
+    #[error("overlay error: {0}")]
+    Overlay(OverlayError),
+}
+
+#[derive(Debug, Error)]
+pub enum QueryError {
+    #[error("notes service error: {0}")]
+    NotesService(NotesServiceHandleError),
+    #[error("graph query error: {0}")]
+    GraphQuery(GraphQueryError),
+}
+
 impl Editor for EditorService {
     type GetNotesError = NotesServiceHandleError;
     type GetNotesFuture =
@@ -28,6 +55,39 @@ impl Editor for EditorService {
         Box::pin(future)
     }
 
+    type GetNotesByTagError = NotesServiceHandleError;
+    type GetNotesByTagFuture =
+        Pin<Box<dyn Future<Output = Result<Vec<NoteItem>, Self::GetNotesByTagError>> + Send>>;
+
+    fn get_notes_by_tag(&mut self, tag: String) -> Self::GetNotesByTagFuture {
+        let notes_service = self.notes_service.clone();
+        let future = async move {
+            notes_service
+                .get_notes_by_tag(tag)
+                .await
+                .map(Option::unwrap_or_default)
+        };
+
+        Box::pin(future)
+    }
+
+    type GraphQueryError = QueryError;
+    type GraphQueryFuture =
+        Pin<Box<dyn Future<Output = Result<GraphQueryResult, Self::GraphQueryError>> + Send>>;
+
+    fn graph_query(&mut self, query: GraphQuery) -> Self::GraphQueryFuture {
+        let notes_service = self.notes_service.clone();
+        let future = async move {
+            notes_service
+                .graph_query(query)
+                .await
+                .map_err(QueryError::NotesService)?
+                .map_err(QueryError::GraphQuery)
+        };
+
+        Box::pin(future)
+    }
+
     type FocusNoteError = NotesServiceHandleError;
     type FocusNoteFuture = Pin<Box<dyn Future<Output = Result<(), Self::FocusNoteError>> + Send>>;
 
@@ -37,4 +97,36 @@ impl Editor for EditorService {
 
         Box::pin(future)
     }
+
+    type DidChangeError = BufferError;
+    type DidChangeFuture = Pin<Box<dyn Future<Output = Result<(), Self::DidChangeError>> + Send>>;
+
+    fn did_change(&mut self, path: PathBuf, changes: Vec<TextChange>) -> Self::DidChangeFuture {
+        let build_service = self.build_service.clone();
+        let future = async move {
+            build_service
+                .did_change(path, changes)
+                .await
+                .map_err(BufferError::BuildService)?
+                .map_err(BufferError::Overlay)
+        };
+
+        Box::pin(future)
+    }
+
+    type DidCloseError = BufferError;
+    type DidCloseFuture = Pin<Box<dyn Future<Output = Result<(), Self::DidCloseError>> + Send>>;
+
+    fn did_close(&mut self, path: PathBuf) -> Self::DidCloseFuture {
+        let build_service = self.build_service.clone();
+        let future = async move {
+            build_service
+                .did_close(path)
+                .await
+                .map_err(BufferError::BuildService)?
+                .map_err(BufferError::Overlay)
+        };
+
+        Box::pin(future)
+    }
 }
@@ -0,0 +1,98 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use walkdir::WalkDir;
+
+/// Where the files of a project are read from. Files from packages and the
+/// build directory always live on disk.
+pub trait FileSource: Send + Sync + 'static {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Lists the files below a directory. Directories for which `descend`
+    /// returns false are skipped.
+    fn walk(&self, directory: &Path, descend: &dyn Fn(&Path) -> bool) -> Vec<PathBuf>;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiskFileSource;
+
+impl FileSource for DiskFileSource {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        if fs::metadata(path)?.is_dir() {
+            return Err(io::Error::from(io::ErrorKind::IsADirectory));
+        }
+
+        fs::read(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn walk(&self, directory: &Path, descend: &dyn Fn(&Path) -> bool) -> Vec<PathBuf> {
+        WalkDir::new(directory)
+            .into_iter()
+            .filter_entry(|entry| !entry.file_type().is_dir() || descend(entry.path()))
+            .filter_map(Result::ok)
+            .filter(|entry| !entry.file_type().is_dir())
+            .map(|entry| entry.into_path())
+            .collect()
+    }
+}
+
+/// Files kept in memory, for running the build without touching the disk.
+/// Clones share the same files.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFileSource(Arc<Mutex<BTreeMap<PathBuf, Vec<u8>>>>);
+
+impl MemoryFileSource {
+    pub fn write(&self, path: impl Into<PathBuf>, contents: impl Into<Vec<u8>>) {
+        self.0.lock().insert(path.into(), contents.into());
+    }
+
+    pub fn remove(&self, path: &Path) -> bool {
+        self.0.lock().remove(path).is_some()
+    }
+}
+
+impl FileSource for MemoryFileSource {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.0
+            .lock()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let files = self.0.lock();
+
+        files.contains_key(path)
+            || files
+                .range(path.to_owned()..)
+                .next()
+                .is_some_and(|(other, _)| other.starts_with(path))
+    }
+
+    fn walk(&self, directory: &Path, descend: &dyn Fn(&Path) -> bool) -> Vec<PathBuf> {
+        self.0
+            .lock()
+            .keys()
+            .filter(|path| path.starts_with(directory))
+            .filter(|path| {
+                path.ancestors()
+                    .skip(1)
+                    .take_while(|ancestor| ancestor.starts_with(directory))
+                    .all(descend)
+            })
+            .cloned()
+            .collect()
+    }
+}
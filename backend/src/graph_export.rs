@@ -0,0 +1,261 @@
+use std::{fmt::Write, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::notes_service::NoteMetadata;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphFormat {
+    Dot,
+    #[value(name = "graphml")]
+    GraphMl,
+    #[default]
+    Json,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Edge<T> {
+    pub source: T,
+    pub target: T,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExportedNote {
+    pub id: Uuid,
+    pub title: String,
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<NoteMetadata>,
+}
+
+/// Source files and the files they depend on, including files from packages.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FileGraph {
+    pub files: Vec<String>,
+    /// Edges go from a file to the files it depends on.
+    pub dependencies: Vec<Edge<String>>,
+}
+
+/// The note link graph, optionally together with the file dependency graph.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GraphExport {
+    pub notes: Vec<ExportedNote>,
+    pub links: Vec<Edge<Uuid>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<FileGraph>,
+}
+
+impl GraphExport {
+    pub fn render(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => self.to_dot(),
+            GraphFormat::GraphMl => self.to_graphml(),
+            GraphFormat::Json => serde_json::to_string_pretty(self).unwrap(),
+        }
+    }
+
+    fn to_dot(&self) -> String {
+        fn quote(s: &str) -> String {
+            let escaped = s
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+
+            format!("\"{escaped}\"")
+        }
+
+        let mut output = String::from("digraph notes {\n");
+
+        for note in &self.notes {
+            let mut attributes = vec![
+                format!("label={}", quote(&note.title)),
+                format!("path={}", quote(&note.path.to_string_lossy())),
+            ];
+            if let Some(metadata) = &note.metadata {
+                if !metadata.tags.is_empty() {
+                    attributes.push(format!("tags={}", quote(&metadata.tags.join(","))));
+                }
+                if let Some(status) = &metadata.status {
+                    attributes.push(format!("status={}", quote(status)));
+                }
+            }
+
+            let _ = writeln!(
+                output,
+                "    {} [{}];",
+                quote(&note.id.to_string()),
+                attributes.join(", ")
+            );
+        }
+        for Edge { source, target } in &self.links {
+            let _ = writeln!(
+                output,
+                "    {} -> {};",
+                quote(&source.to_string()),
+                quote(&target.to_string())
+            );
+        }
+
+        if let Some(files) = &self.files {
+            output.push_str("    subgraph cluster_files {\n        label=\"files\";\n");
+            for file in &files.files {
+                let _ = writeln!(
+                    output,
+                    "        {} [label={}, shape=box];",
+                    quote(&format!("file:{file}")),
+                    quote(file)
+                );
+            }
+            for Edge { source, target } in &files.dependencies {
+                let _ = writeln!(
+                    output,
+                    "        {} -> {};",
+                    quote(&format!("file:{source}")),
+                    quote(&format!("file:{target}"))
+                );
+            }
+            output.push_str("    }\n");
+        }
+
+        output.push_str("}\n");
+
+        output
+    }
+
+    fn to_graphml(&self) -> String {
+        fn escape(s: &str) -> String {
+            s.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+                // Attribute values would have their line breaks normalised
+                // to spaces
+                .replace('\n', "&#10;")
+        }
+
+        let mut output = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+            "  <key id=\"kind\" for=\"all\" attr.name=\"kind\" attr.type=\"string\"/>\n",
+            "  <key id=\"title\" for=\"node\" attr.name=\"title\" attr.type=\"string\"/>\n",
+            "  <key id=\"path\" for=\"node\" attr.name=\"path\" attr.type=\"string\"/>\n",
+            "  <key id=\"tags\" for=\"node\" attr.name=\"tags\" attr.type=\"string\"/>\n",
+            "  <key id=\"status\" for=\"node\" attr.name=\"status\" attr.type=\"string\"/>\n",
+            "  <graph id=\"notes\" edgedefault=\"directed\">\n",
+        ));
+
+        for note in &self.notes {
+            let _ = writeln!(output, "    <node id=\"{}\">", note.id);
+            let _ = writeln!(output, "      <data key=\"kind\">note</data>");
+            let _ = writeln!(
+                output,
+                "      <data key=\"title\">{}</data>",
+                escape(&note.title)
+            );
+            let _ = writeln!(
+                output,
+                "      <data key=\"path\">{}</data>",
+                escape(&note.path.to_string_lossy())
+            );
+            if let Some(metadata) = &note.metadata {
+                if !metadata.tags.is_empty() {
+                    let _ = writeln!(
+                        output,
+                        "      <data key=\"tags\">{}</data>",
+                        escape(&metadata.tags.join(","))
+                    );
+                }
+                if let Some(status) = &metadata.status {
+                    let _ = writeln!(
+                        output,
+                        "      <data key=\"status\">{}</data>",
+                        escape(status)
+                    );
+                }
+            }
+            output.push_str("    </node>\n");
+        }
+        for Edge { source, target } in &self.links {
+            let _ = writeln!(
+                output,
+                "    <edge source=\"{source}\" target=\"{target}\"><data key=\"kind\">link</data></edge>"
+            );
+        }
+
+        if let Some(files) = &self.files {
+            for file in &files.files {
+                let _ = writeln!(
+                    output,
+                    "    <node id=\"file:{}\"><data key=\"kind\">file</data><data key=\"path\">{}</data></node>",
+                    escape(file),
+                    escape(file)
+                );
+            }
+            for Edge { source, target } in &files.dependencies {
+                let _ = writeln!(
+                    output,
+                    "    <edge source=\"file:{}\" target=\"file:{}\"><data key=\"kind\">dependency</data></edge>",
+                    escape(source),
+                    escape(target)
+                );
+            }
+        }
+
+        output.push_str("  </graph>\n</graphml>\n");
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AWKWARD: &str = "\"Quotes\" <tags> & \\ back\nslash";
+
+    fn export() -> GraphExport {
+        GraphExport {
+            notes: vec![ExportedNote {
+                id: Uuid::from_u128(1),
+                title: AWKWARD.into(),
+                path: PathBuf::from("notes/a.typ"),
+                metadata: None,
+            }],
+            links: Vec::new(),
+            files: Some(FileGraph {
+                files: vec![AWKWARD.into(), "notes/a.typ".into()],
+                dependencies: vec![Edge {
+                    source: "notes/a.typ".into(),
+                    target: AWKWARD.into(),
+                }],
+            }),
+        }
+    }
+
+    #[test]
+    fn dot_escapes_strings() {
+        let dot = export().render(GraphFormat::Dot);
+        let escaped = r#""\"Quotes\" <tags> & \\ back\nslash""#;
+
+        assert!(dot.contains(&format!("[label={escaped}, path=\"notes/a.typ\"]")));
+        assert!(dot.contains(r#""file:notes/a.typ" -> "file:\"Quotes\" <tags> & \\ back\nslash""#));
+        // Every line is a statement, nothing spilled over
+        assert!(dot.lines().all(|line| {
+            let line = line.trim();
+            line.ends_with(';') || line.ends_with('{') || line == "}"
+        }));
+    }
+
+    #[test]
+    fn graphml_escapes_strings() {
+        let graphml = export().render(GraphFormat::GraphMl);
+        let escaped = "&quot;Quotes&quot; &lt;tags&gt; &amp; \\ back&#10;slash";
+
+        assert!(graphml.contains(&format!("<data key=\"title\">{escaped}</data>")));
+        assert!(graphml.contains(&format!("<node id=\"file:{escaped}\">")));
+        assert!(graphml.contains(&format!("target=\"file:{escaped}\">")));
+        assert!(!graphml.contains("back\nslash"));
+    }
+}
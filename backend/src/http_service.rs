@@ -1,26 +1,54 @@
-use std::io;
+use std::{io, sync::Arc};
 
 use axum::{
     Router,
     body::Body,
     extract::{
-        Path, State,
+        FromRef, Path, Query, State,
         ws::{self, Message, WebSocket},
     },
-    response::{Html, IntoResponse},
+    response::{Html, IntoResponse, Json},
     routing::{any, get},
 };
 use http::{Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::sync::broadcast;
-use tower_http::cors;
+use tower_http::{cors, trace::TraceLayer};
+use tracing::warn;
 use uuid::Uuid;
 
+use crate::build_service::{BuildServiceHandle, BuildServiceHandleError};
+use crate::graph_export::{GraphExport, GraphFormat};
+use crate::metrics::{Gauges, Metrics};
 use crate::notes_service::{
-    Initialize, NoteMessage, NoteUpdate, NotesServiceHandle, NotesServiceHandleError,
+    GraphQuery, GraphQueryError, GraphQueryResult, Initialize, NoteDetails, NoteItem, NoteMessage,
+    NoteUpdate, NotesServiceHandle, NotesServiceHandleError, TagItem,
 };
 
+struct GetNoteResponse {
+    result: Result<Option<NoteDetails>, NotesServiceHandleError>,
+}
+
+impl IntoResponse for GetNoteResponse {
+    fn into_response(self) -> Response<Body> {
+        match self.result {
+            Ok(Some(note)) => IntoResponse::into_response(Json(note)),
+            Ok(None) => IntoResponse::into_response(StatusCode::NOT_FOUND),
+            Err(_) => IntoResponse::into_response(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+async fn get_note(
+    State(notes_service): State<NotesServiceHandle>,
+    Path(id): Path<Uuid>,
+) -> GetNoteResponse {
+    let result = notes_service.get_note(id).await;
+
+    GetNoteResponse { result }
+}
+
 struct GetNoteContentResponse {
     result: Result<Result<Option<String>, io::Error>, NotesServiceHandleError>,
 }
@@ -44,6 +72,210 @@ async fn get_note_content(
     GetNoteContentResponse { result }
 }
 
+struct GetTagsResponse {
+    result: Result<Vec<TagItem>, NotesServiceHandleError>,
+}
+
+impl IntoResponse for GetTagsResponse {
+    fn into_response(self) -> Response<Body> {
+        match self.result {
+            Ok(tags) => IntoResponse::into_response(Json(tags)),
+            Err(_) => IntoResponse::into_response(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+async fn get_tags(State(notes_service): State<NotesServiceHandle>) -> GetTagsResponse {
+    let result = notes_service.get_tags().await;
+
+    GetTagsResponse { result }
+}
+
+struct GetNotesByTagResponse {
+    result: Result<Option<Vec<NoteItem>>, NotesServiceHandleError>,
+}
+
+impl IntoResponse for GetNotesByTagResponse {
+    fn into_response(self) -> Response<Body> {
+        match self.result {
+            Ok(Some(notes)) => IntoResponse::into_response(Json(notes)),
+            Ok(None) => IntoResponse::into_response(StatusCode::NOT_FOUND),
+            Err(_) => IntoResponse::into_response(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+async fn get_notes_by_tag(
+    State(notes_service): State<NotesServiceHandle>,
+    Path(tag): Path<String>,
+) -> GetNotesByTagResponse {
+    let result = notes_service.get_notes_by_tag(tag).await;
+
+    GetNotesByTagResponse { result }
+}
+
+struct GraphQueryResponse {
+    result: Result<Result<GraphQueryResult, GraphQueryError>, NotesServiceHandleError>,
+}
+
+impl IntoResponse for GraphQueryResponse {
+    fn into_response(self) -> Response<Body> {
+        match self.result {
+            Ok(Ok(result)) => IntoResponse::into_response(Json(result)),
+            Ok(Err(GraphQueryError::UnknownNote(_))) => {
+                IntoResponse::into_response(StatusCode::NOT_FOUND)
+            }
+            Err(_) => IntoResponse::into_response(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+async fn graph_query(notes_service: NotesServiceHandle, query: GraphQuery) -> GraphQueryResponse {
+    let result = notes_service.graph_query(query).await;
+
+    GraphQueryResponse { result }
+}
+
+#[derive(Deserialize)]
+struct NeighborhoodParameters {
+    depth: Option<usize>,
+}
+
+async fn get_neighborhood(
+    State(notes_service): State<NotesServiceHandle>,
+    Path(id): Path<Uuid>,
+    Query(NeighborhoodParameters { depth }): Query<NeighborhoodParameters>,
+) -> GraphQueryResponse {
+    let depth = depth.unwrap_or(1);
+
+    graph_query(notes_service, GraphQuery::Neighborhood { id, depth }).await
+}
+
+async fn get_shortest_path(
+    State(notes_service): State<NotesServiceHandle>,
+    Path((from, to)): Path<(Uuid, Uuid)>,
+) -> GraphQueryResponse {
+    graph_query(notes_service, GraphQuery::ShortestPath { from, to }).await
+}
+
+async fn get_orphans(State(notes_service): State<NotesServiceHandle>) -> GraphQueryResponse {
+    graph_query(notes_service, GraphQuery::Orphans).await
+}
+
+async fn get_unreachable(State(notes_service): State<NotesServiceHandle>) -> GraphQueryResponse {
+    graph_query(notes_service, GraphQuery::Unreachable).await
+}
+
+async fn get_clusters(State(notes_service): State<NotesServiceHandle>) -> GraphQueryResponse {
+    graph_query(notes_service, GraphQuery::Clusters).await
+}
+
+#[derive(Debug, Error)]
+enum ExportGraphError {
+    #[error("NotesService error: {0}")]
+    NotesService(NotesServiceHandleError),
+    #[error("BuildService error: {0}")]
+    BuildService(BuildServiceHandleError),
+}
+
+struct ExportGraphResponse {
+    format: GraphFormat,
+    result: Result<GraphExport, ExportGraphError>,
+}
+
+impl IntoResponse for ExportGraphResponse {
+    fn into_response(self) -> Response<Body> {
+        let content_type = match self.format {
+            GraphFormat::Dot => "text/vnd.graphviz",
+            GraphFormat::GraphMl => "application/graphml+xml",
+            GraphFormat::Json => "application/json",
+        };
+
+        match self.result {
+            Ok(export) => IntoResponse::into_response((
+                [(http::header::CONTENT_TYPE, content_type)],
+                export.render(self.format),
+            )),
+            Err(_) => IntoResponse::into_response(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportGraphParameters {
+    #[serde(default)]
+    format: GraphFormat,
+    #[serde(default)]
+    metadata: bool,
+    #[serde(default)]
+    files: bool,
+}
+
+async fn export_graph(
+    State(notes_service): State<NotesServiceHandle>,
+    State(build_service): State<BuildServiceHandle>,
+    Query(ExportGraphParameters {
+        format,
+        metadata,
+        files,
+    }): Query<ExportGraphParameters>,
+) -> ExportGraphResponse {
+    let result = async {
+        let mut export = notes_service
+            .export_graph(metadata)
+            .await
+            .map_err(ExportGraphError::NotesService)?;
+
+        if files {
+            export.files = Some(
+                build_service
+                    .dependency_graph()
+                    .await
+                    .map_err(ExportGraphError::BuildService)?,
+            );
+        }
+
+        Ok(export)
+    }
+    .await;
+
+    ExportGraphResponse { format, result }
+}
+
+async fn get_health(State(notes_service): State<NotesServiceHandle>) -> impl IntoResponse {
+    match notes_service.get_build_finished().await {
+        Ok(event) if event.has_occured() => (StatusCode::OK, "ok"),
+        Ok(_) => (StatusCode::SERVICE_UNAVAILABLE, "building"),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+    }
+}
+
+async fn get_metrics(
+    State(notes_service): State<NotesServiceHandle>,
+    State(build_service): State<BuildServiceHandle>,
+    State(metrics): State<Arc<Metrics>>,
+) -> impl IntoResponse {
+    let notes_queue_depth = notes_service.queue_depth();
+    let build_queue_depth = build_service.queue_depth();
+
+    match notes_service.get_totals().await {
+        Ok((notes, links)) => {
+            let gauges = Gauges {
+                notes,
+                links,
+                notes_queue_depth,
+                build_queue_depth,
+            };
+
+            IntoResponse::into_response((
+                [(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                metrics.render(&gauges),
+            ))
+        }
+        Err(_) => IntoResponse::into_response(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
 #[derive(Debug, Error)]
 enum HandleUpdateError {
     #[error("WebSocket error: {0}")]
@@ -65,10 +297,13 @@ pub enum WebsocketMessage {
     Remove(Vec<Uuid>),
     #[serde(rename(serialize = "focus"))]
     Focus(Uuid),
+    #[serde(rename(serialize = "default_note"))]
+    DefaultNote(Uuid),
 }
 
 async fn handle_updates_helper(
     notes_service: NotesServiceHandle,
+    metrics: &Metrics,
     mut socket: WebSocket,
 ) -> Result<(), HandleUpdateError> {
     let build_finished = notes_service
@@ -110,6 +345,7 @@ async fn handle_updates_helper(
                     NoteMessage::Update(updates) => WebsocketMessage::Update(updates),
                     NoteMessage::Remove(removes) => WebsocketMessage::Remove(removes),
                     NoteMessage::Focus(id) => WebsocketMessage::Focus(id),
+                    NoteMessage::DefaultNote(id) => WebsocketMessage::DefaultNote(id),
                 };
                 let content = serde_json::to_string(&payload).unwrap();
 
@@ -118,8 +354,28 @@ async fn handle_updates_helper(
                     .await
                     .map_err(HandleUpdateError::WebSocketError)?;
             }
-            Err(broadcast::error::RecvError::Lagged(lag_error)) => {
-                panic!("Lag error occurred {:?}", lag_error);
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                // The client missed updates, so start it over from the
+                // current state
+                metrics.broadcast_lagged();
+                warn!(
+                    skipped,
+                    "websocket client fell behind, sending it the full state"
+                );
+
+                let (initialize, resubscribed) = notes_service
+                    .subscribe()
+                    .await
+                    .map_err(HandleUpdateError::NotesServiceError)?;
+                receiver = resubscribed;
+
+                let payload = WebsocketMessage::Initialize(initialize);
+                let content = serde_json::to_string(&payload).unwrap();
+
+                socket
+                    .send(Message::Text(content.into()))
+                    .await
+                    .map_err(HandleUpdateError::WebSocketError)?;
             }
             Err(broadcast::error::RecvError::Closed) => break Ok(()),
         }
@@ -128,24 +384,72 @@ async fn handle_updates_helper(
 
 async fn handle_updates(
     State(notes_service): State<NotesServiceHandle>,
+    State(metrics): State<Arc<Metrics>>,
     websocket: ws::WebSocketUpgrade,
 ) -> impl IntoResponse {
     websocket.on_upgrade(async move |socket| {
-        if let Err(error) = handle_updates_helper(notes_service, socket).await {
-            println!("Error in websocket handler: {:?}", error);
+        let _client = metrics.websocket_client();
+
+        if let Err(error) = handle_updates_helper(notes_service, &metrics, socket).await {
+            warn!(%error, "websocket connection failed");
         }
     })
 }
 
-pub fn router(actor: NotesServiceHandle) -> Router<()> {
+#[derive(Clone)]
+struct HttpState {
+    notes_service: NotesServiceHandle,
+    build_service: BuildServiceHandle,
+    metrics: Arc<Metrics>,
+}
+
+impl FromRef<HttpState> for NotesServiceHandle {
+    fn from_ref(state: &HttpState) -> Self {
+        state.notes_service.clone()
+    }
+}
+
+impl FromRef<HttpState> for BuildServiceHandle {
+    fn from_ref(state: &HttpState) -> Self {
+        state.build_service.clone()
+    }
+}
+
+impl FromRef<HttpState> for Arc<Metrics> {
+    fn from_ref(state: &HttpState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+pub fn router(
+    notes_service: NotesServiceHandle,
+    build_service: BuildServiceHandle,
+    metrics: Arc<Metrics>,
+) -> Router<()> {
     let cors = cors::CorsLayer::new()
         .allow_origin(cors::Any)
         .allow_methods([http::Method::GET, http::Method::POST])
         .allow_headers(cors::Any);
 
     Router::new()
+        .route("/api/notes/{id}", get(get_note))
         .route("/api/notes/{id}/content", get(get_note_content))
+        .route("/healthz", get(get_health))
+        .route("/metrics", get(get_metrics))
+        .route("/api/graph", get(export_graph))
+        .route("/api/graph/neighborhood/{id}", get(get_neighborhood))
+        .route("/api/graph/path/{from}/{to}", get(get_shortest_path))
+        .route("/api/graph/orphans", get(get_orphans))
+        .route("/api/graph/unreachable", get(get_unreachable))
+        .route("/api/graph/clusters", get(get_clusters))
+        .route("/api/tags", get(get_tags))
+        .route("/api/tags/{tag}", get(get_notes_by_tag))
         .route("/api/updates", any(handle_updates))
-        .with_state(actor)
+        .with_state(HttpState {
+            notes_service,
+            build_service,
+            metrics,
+        })
         .layer(cors)
+        .layer(TraceLayer::new_for_http())
 }
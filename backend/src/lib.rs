@@ -1,12 +1,22 @@
+pub mod app;
 pub mod config;
+pub mod config_watcher;
 
 pub mod event;
+pub mod file_source;
+pub mod metrics;
 pub mod package;
+pub mod retry;
+pub mod source_filter;
 pub mod system_world;
 
 pub mod editor_protocol;
 
 pub mod build_service;
+pub mod dependency_graph;
 pub mod editor_service;
+pub mod graph_export;
 pub mod http_service;
 pub mod notes_service;
+
+pub use app::{Phelps, PhelpsBuilder, PhelpsError, Stopped};
@@ -1,86 +1,194 @@
-use std::error::Error;
+use std::{
+    env,
+    error::Error,
+    fs::{self, OpenOptions},
+    io,
+    path::Path,
+    process::{self, ExitCode},
+    sync::{Arc, Mutex},
+};
 
 use clap::Parser;
 use phelps::build_service::BuildService;
-use phelps::editor_protocol::{EditorServer, EditorServiceWrapper};
-use phelps::editor_service::EditorService;
-use phelps::{http_service::router, notes_service::NotesServiceHandle};
+use phelps::graph_export::GraphFormat;
+use phelps::notes_service::NotesServiceHandle;
+use phelps::package::migrate_downloaded_packages;
+use phelps::system_world::search_fonts;
+use phelps::{Phelps, Stopped};
 use tokio::runtime::Runtime;
-use tokio::{net::TcpListener, signal};
+use tokio::signal;
 
-use phelps::config::{Arguments, Commands, Config};
-use tokio_util::{sync::CancellationToken, task::TaskTracker};
-use tower::make::Shared;
+use phelps::config::{Arguments, Commands, Config, ConfigCommand};
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+use tracing_subscriber::{EnvFilter, Layer, layer::SubscriberExt, util::SubscriberInitExt};
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> Result<ExitCode, Box<dyn Error>> {
     let arguments = Arguments::try_parse()?;
-    let config = Config::try_build()?;
+    init_logging(arguments.verbose, arguments.log_file.as_deref())?;
+    let config = Config::try_build(&arguments)?;
 
     match arguments.command {
         Commands::Watch => watch(config),
+        Commands::Fonts { variants } => fonts(config, variants).map(|()| ExitCode::SUCCESS),
+        Commands::Graph {
+            format,
+            metadata,
+            files,
+        } => graph(config, format, metadata, files).map(|()| ExitCode::SUCCESS),
+        Commands::Config {
+            command: ConfigCommand::Show,
+        } => config_show(config).map(|()| ExitCode::SUCCESS),
     }
 }
 
-fn watch(config: Config) -> Result<(), Box<dyn Error>> {
-    let runtime = Runtime::new()?;
+/// Logs to stderr, and as JSON to `log_file` if given.
+fn init_logging(verbose: u8, log_file: Option<&Path>) -> Result<(), io::Error> {
+    let filter = || {
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+            let level = match verbose {
+                0 => "info",
+                1 => "debug",
+                _ => "trace",
+            };
+
+            EnvFilter::new(format!("warn,phelps={level},tower_http={level}"))
+        })
+    };
+
+    let stderr = tracing_subscriber::fmt::layer()
+        .with_writer(io::stderr)
+        .with_filter(filter());
+    let file = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_writer(Mutex::new(file))
+                    .with_filter(filter()),
+            )
+        }
+        None => None,
+    };
 
-    runtime.block_on(async {
-        let cancel = CancellationToken::new();
-        let tracker = TaskTracker::new();
+    tracing_subscriber::registry()
+        .with(stderr)
+        .with(file)
+        .init();
 
-        {
-            let cancel = cancel.clone();
+    Ok(())
+}
 
-            tracker.spawn(async move {
-                tokio::select! {
-                    _ = signal::ctrl_c() => cancel.cancel(),
-                    _ = cancel.cancelled() => ()
-                }
-            });
-        }
+fn graph(
+    mut config: Config,
+    format: GraphFormat,
+    metadata: bool,
+    files: bool,
+) -> Result<(), Box<dyn Error>> {
+    migrate_downloaded_packages(&config.data_directory, &config.cache_directory)?;
+
+    // Build into a scratch directory so that a running `phelps watch` keeps
+    // its fragments
+    let build_subdirectory = env::temp_dir().join(format!("phelps-graph-{}", process::id()));
+    config.build_subdirectory = build_subdirectory.clone();
+
+    let runtime = Runtime::new()?;
 
+    let export = runtime.block_on(async {
+        let cancel = CancellationToken::new();
         let (notes_service_handle, notes_service) = NotesServiceHandle::build(
             cancel.clone(),
             config.build_subdirectory.clone(),
             config.project_directory.clone(),
             config.default_note,
         );
-        let mut source_directories = Vec::with_capacity(1 + config.extra_directories.len());
-        source_directories.push(config.notes_subdirectory.clone());
-        source_directories.extend(config.extra_directories.clone());
-        let build_service = BuildService::try_build(
-            config.project_directory,
-            source_directories,
-            config.build_subdirectory,
-            config.cache_directory,
-            config.data_directory,
+        let (_, mut build_service) = BuildService::try_build(
+            config,
             runtime.handle().clone(),
             notes_service_handle.clone(),
-            cancel.clone(),
+            Arc::default(),
+            cancel,
         )?;
+        let notes_service = tokio::spawn(notes_service.run());
 
-        tracker.spawn(build_service.run());
-        tracker.spawn(notes_service.run());
+        build_service.build_all().await?;
 
-        let listener = TcpListener::bind("127.0.0.1:3001").await?;
-        let editor_service = Shared::new(EditorServiceWrapper(EditorService::new(
-            notes_service_handle.clone(),
-        )));
-        let editor = EditorServer::new(listener, editor_service, cancel.clone());
+        let mut export = notes_service_handle.export_graph(metadata).await?;
+        if files {
+            export.files = Some(build_service.dependency_graph());
+        }
+
+        // The notes service stops once every handle is gone
+        drop(build_service);
+        drop(notes_service_handle);
+        notes_service.await?;
 
-        tracker.spawn(editor.run());
+        Ok::<_, Box<dyn Error>>(export)
+    });
+    let _ = fs::remove_dir_all(&build_subdirectory);
 
-        let router = router(notes_service_handle);
-        let listener = TcpListener::bind("127.0.0.1:3000").await?;
-        let http = axum::serve(listener, router)
-            .with_graceful_shutdown(cancel.cancelled_owned())
-            .into_future();
+    print!("{}", export?.render(format));
 
-        tracker.spawn(http);
+    Ok(())
+}
 
-        tracker.close();
-        tracker.wait().await;
+fn config_show(config: Config) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = &config.path {
+        println!("# Read from {}", path.display());
+    }
+    print!("{}", toml::to_string_pretty(&config.to_toml())?);
 
-        Ok(())
+    Ok(())
+}
+
+fn fonts(config: Config, variants: bool) -> Result<(), Box<dyn Error>> {
+    let fonts = search_fonts(&config.fonts);
+
+    for (family, infos) in fonts.book.families() {
+        println!("{family}");
+
+        if variants {
+            for info in infos {
+                let variant = info.variant;
+
+                println!(
+                    "- Style: {:?}, Weight: {}, Stretch: {:?}",
+                    variant.style,
+                    variant.weight.to_number(),
+                    variant.stretch.to_ratio()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Exit code used when the services stopped themselves because the build
+/// directory kept failing.
+const BUILD_DIRECTORY_FAILURE: u8 = 2;
+
+fn watch(config: Config) -> Result<ExitCode, Box<dyn Error>> {
+    let runtime = Runtime::new()?;
+
+    runtime.block_on(async {
+        let phelps = Phelps::builder(config).build().await?;
+        let shutdown = async {
+            let _ = signal::ctrl_c().await;
+        };
+
+        match phelps.run(shutdown).await {
+            Stopped::Shutdown => Ok(ExitCode::SUCCESS),
+            Stopped::BuildDirectoryFailure => {
+                error!(
+                    "stopped because the build directory couldn't be written to, check that it \
+                     exists and is writable"
+                );
+
+                Ok(ExitCode::from(BUILD_DIRECTORY_FAILURE))
+            }
+        }
     })
 }
@@ -0,0 +1,218 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+
+const DURATION_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+const FAN_OUT_BUCKETS: [f64; 8] = [1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0];
+
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, output: &mut String, name: &str) {
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(output, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(output, "{name}_bucket{{le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(output, "{name}_sum {}", self.sum);
+        let _ = writeln!(output, "{name}_count {}", self.count);
+    }
+}
+
+#[derive(Default)]
+struct FileCompiles {
+    seconds: f64,
+    count: u64,
+}
+
+/// Counters for one phelps stack, rendered in the Prometheus text format by
+/// `/metrics`.
+pub struct Metrics {
+    compile_durations: Mutex<Histogram>,
+    file_compiles: Mutex<BTreeMap<String, FileCompiles>>,
+    rebuild_fan_out: Mutex<Histogram>,
+    websocket_clients: AtomicU64,
+    broadcast_lag_events: AtomicU64,
+    package_downloads: AtomicU64,
+    package_download_failures: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            compile_durations: Mutex::new(Histogram::new(&DURATION_BUCKETS)),
+            file_compiles: Mutex::default(),
+            rebuild_fan_out: Mutex::new(Histogram::new(&FAN_OUT_BUCKETS)),
+            websocket_clients: AtomicU64::new(0),
+            broadcast_lag_events: AtomicU64::new(0),
+            package_downloads: AtomicU64::new(0),
+            package_download_failures: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Values which are read from the services when the metrics are scraped.
+pub struct Gauges {
+    pub notes: usize,
+    pub links: usize,
+    pub notes_queue_depth: usize,
+    pub build_queue_depth: usize,
+}
+
+/// Counts a websocket client for as long as it's alive.
+pub struct WebsocketClientGuard(Arc<Metrics>);
+
+impl Drop for WebsocketClientGuard {
+    fn drop(&mut self) {
+        self.0.websocket_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn observe_compile(&self, file: String, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        self.compile_durations.lock().observe(seconds);
+
+        let mut file_compiles = self.file_compiles.lock();
+        let compiles = file_compiles.entry(file).or_default();
+        compiles.seconds += seconds;
+        compiles.count += 1;
+    }
+
+    pub fn observe_rebuild(&self, files: usize) {
+        self.rebuild_fan_out.lock().observe(files as f64);
+    }
+
+    pub fn websocket_client(self: &Arc<Self>) -> WebsocketClientGuard {
+        self.websocket_clients.fetch_add(1, Ordering::Relaxed);
+
+        WebsocketClientGuard(self.clone())
+    }
+
+    pub fn broadcast_lagged(&self) {
+        self.broadcast_lag_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn package_downloaded(&self, success: bool) {
+        self.package_downloads.fetch_add(1, Ordering::Relaxed);
+
+        if !success {
+            self.package_download_failures
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut output = String::new();
+
+        output.push_str("# HELP phelps_compile_duration_seconds Time spent building a file.\n");
+        output.push_str("# TYPE phelps_compile_duration_seconds histogram\n");
+        self.compile_durations
+            .lock()
+            .render(&mut output, "phelps_compile_duration_seconds");
+
+        output.push_str("# HELP phelps_file_compile_seconds Time spent building each file.\n");
+        output.push_str("# TYPE phelps_file_compile_seconds summary\n");
+        for (file, compiles) in self.file_compiles.lock().iter() {
+            let file = file.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(
+                output,
+                "phelps_file_compile_seconds_sum{{file=\"{file}\"}} {}",
+                compiles.seconds
+            );
+            let _ = writeln!(
+                output,
+                "phelps_file_compile_seconds_count{{file=\"{file}\"}} {}",
+                compiles.count
+            );
+        }
+
+        output.push_str("# HELP phelps_rebuild_files Files built because of one change.\n");
+        output.push_str("# TYPE phelps_rebuild_files histogram\n");
+        self.rebuild_fan_out
+            .lock()
+            .render(&mut output, "phelps_rebuild_files");
+
+        let counters = [
+            (
+                "phelps_broadcast_lag_events_total",
+                "Times a websocket client fell behind on updates.",
+                &self.broadcast_lag_events,
+            ),
+            (
+                "phelps_package_downloads_total",
+                "Package downloads, including failed ones.",
+                &self.package_downloads,
+            ),
+            (
+                "phelps_package_download_failures_total",
+                "Package downloads which failed.",
+                &self.package_download_failures,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(output, "# HELP {name} {help}");
+            let _ = writeln!(output, "# TYPE {name} counter");
+            let _ = writeln!(output, "{name} {}", value.load(Ordering::Relaxed));
+        }
+
+        let values = [
+            (
+                "phelps_websocket_clients",
+                "Connected websocket clients.",
+                self.websocket_clients.load(Ordering::Relaxed) as usize,
+            ),
+            ("phelps_notes", "Notes in the vault.", gauges.notes),
+            ("phelps_links", "Links between notes.", gauges.links),
+            (
+                "phelps_notes_queue_depth",
+                "Messages waiting for the notes service.",
+                gauges.notes_queue_depth,
+            ),
+            (
+                "phelps_build_queue_depth",
+                "Messages waiting for the build service.",
+                gauges.build_queue_depth,
+            ),
+        ];
+        for (name, help, value) in values {
+            let _ = writeln!(output, "# HELP {name} {help}");
+            let _ = writeln!(output, "# TYPE {name} gauge");
+            let _ = writeln!(output, "{name} {value}");
+        }
+
+        output
+    }
+}
@@ -1,6 +1,16 @@
-use std::{collections::HashMap, io, path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque, hash_map::Entry},
+    io,
+    path::PathBuf,
+    sync::Arc,
+};
 
-use petgraph::prelude::DiGraphMap;
+use petgraph::{
+    Direction,
+    algo::{astar, tarjan_scc},
+    prelude::DiGraphMap,
+    visit::Dfs,
+};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::{
@@ -8,18 +18,31 @@ use tokio::{
     sync::{broadcast, mpsc, oneshot},
 };
 use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
 use typst::syntax::FileId;
 use uuid::Uuid;
 
-use crate::event::Event;
+use crate::{
+    event::Event,
+    graph_export::{Edge, ExportedNote, GraphExport},
+    retry::{MAX_CONSECUTIVE_FAILURES, retry},
+};
 
 struct NotesServiceState {
     cancel: CancellationToken,
+    /// Removals in a row which failed to delete their fragments.
+    failed_removals: usize,
     links: DiGraphMap<Uuid, ()>,
     project_directory: PathBuf,
     build_subdirectory: PathBuf,
     default_note: Uuid,
     titles: HashMap<Uuid, String>,
+    parents: HashMap<Uuid, Uuid>,
+    metadata: HashMap<Uuid, NoteMetadata>,
+    tags: BTreeMap<String, BTreeSet<Uuid>>,
+    outlines: HashMap<Uuid, Vec<OutlineEntry>>,
+    /// Notes whose latest build couldn't be written to the build directory.
+    write_errors: HashMap<Uuid, String>,
     file_ids: HashMap<Uuid, FileId>,
     ids: HashMap<FileId, Vec<Uuid>>,
     errors: HashMap<FileId, Result<Vec<String>, Vec<String>>>,
@@ -29,7 +52,8 @@ struct NotesServiceState {
 
 impl NotesServiceState {
     async fn get_note_content(&mut self, id: Uuid) -> Result<Option<String>, io::Error> {
-        if self.links.contains_node(id) {
+        // Dangling link targets are nodes too, but have no fragment
+        if self.titles.contains_key(&id) {
             let path = self.build_subdirectory.join(format!("{}.html", id));
 
             let content = fs::read_to_string(path).await?;
@@ -97,6 +121,11 @@ impl NotesServiceState {
             title,
             id: i,
             links,
+            parent,
+            metadata,
+            outline,
+            error,
+            ..
         }: NoteData,
     ) {
         self.links.add_node(i);
@@ -113,36 +142,90 @@ impl NotesServiceState {
         self.ids.get_mut(&file_id).unwrap().push(i);
         self.file_ids.insert(i, file_id);
         self.titles.insert(i, title);
+        match parent {
+            Some(parent) => self.parents.insert(i, parent),
+            None => self.parents.remove(&i),
+        };
+        self.unindex_tags(i);
+        for tag in &metadata.tags {
+            self.tags.entry(tag.clone()).or_default().insert(i);
+        }
+        self.metadata.insert(i, metadata);
+        self.outlines.insert(i, outline);
+        match error {
+            Some(error) => self.write_errors.insert(i, error),
+            None => self.write_errors.remove(&i),
+        };
     }
 
-    fn update_notes(
-        &mut self,
-        updates: Vec<(FileId, Result<(Vec<String>, Vec<NoteData>), Vec<String>>)>,
-    ) {
+    /// Removes a note from the tag index, dropping tags left without notes.
+    fn unindex_tags(&mut self, i: Uuid) {
+        let Some(metadata) = self.metadata.get(&i) else {
+            return;
+        };
+
+        for tag in &metadata.tags {
+            if let Some(is) = self.tags.get_mut(tag) {
+                is.remove(&i);
+
+                if is.is_empty() {
+                    self.tags.remove(tag);
+                }
+            }
+        }
+    }
+
+    async fn update_notes(&mut self, updates: Vec<(FileId, BuildResult)>) {
         let mut data: Vec<NoteUpdate> = Vec::new();
+        let mut removes: Vec<Vec<Uuid>> = Vec::new();
 
         for (file_id, result) in updates {
             match result {
                 Ok((warnings, outputs)) => {
-                    data.extend(
-                        outputs
-                            .iter()
-                            .cloned()
-                            .map(|NoteData { id, title, links }| NoteUpdate {
-                                id,
-                                title,
-                                links,
-                                warnings: warnings.clone(),
-                                errors: Vec::new(),
-                            }),
-                    );
+                    data.extend(outputs.iter().cloned().map(
+                        |NoteData {
+                             id,
+                             title,
+                             links,
+                             parent,
+                             children,
+                             metadata,
+                             outline,
+                             error,
+                             ..
+                         }| NoteUpdate {
+                            id,
+                            title,
+                            links,
+                            parent,
+                            children,
+                            metadata,
+                            outline,
+                            warnings: warnings.clone(),
+                            errors: error.into_iter().collect(),
+                        },
+                    ));
 
                     self.errors.insert(file_id, Ok(warnings));
-                    self.ids.entry(file_id).or_default().clear();
+                    let previous = self.ids.insert(file_id, Vec::new()).unwrap_or_default();
 
                     for data in outputs {
                         self.update_note(file_id, data);
                     }
+
+                    // Notes which were removed from the file, unless they moved
+                    // to another file which was built first
+                    let removed: Vec<Uuid> = previous
+                        .into_iter()
+                        .filter(|i| self.file_ids.get(i) == Some(&file_id))
+                        .filter(|i| !self.ids[&file_id].contains(i))
+                        .collect();
+                    for &i in &removed {
+                        self.forget_note(i);
+                    }
+                    if !removed.is_empty() {
+                        removes.push(removed);
+                    }
                 }
                 Err(errors) => {
                     if let Some(ids) = self.ids.get(&file_id) {
@@ -151,6 +234,10 @@ impl NotesServiceState {
                                 id,
                                 title: self.titles.get(&id).unwrap().clone(),
                                 links: Vec::new(),
+                                parent: self.parents.get(&id).copied(),
+                                children: Vec::new(),
+                                metadata: self.metadata.get(&id).cloned().unwrap_or_default(),
+                                outline: self.outlines.get(&id).cloned().unwrap_or_default(),
                                 warnings: Vec::new(),
                                 errors: errors.clone(),
                             };
@@ -166,39 +253,77 @@ impl NotesServiceState {
         if self.build_finished_event.has_occured() && !data.is_empty() {
             let _ = self.updates.send(NoteMessage::Update(data));
         }
+        for removed in removes {
+            self.remove_fragments(&removed).await;
+            let _ = self.updates.send(NoteMessage::Remove(removed));
+        }
+    }
+
+    /// Drops everything known about a note.
+    fn forget_note(&mut self, i: Uuid) {
+        self.titles.remove(&i);
+        self.parents.remove(&i);
+        self.unindex_tags(i);
+        self.metadata.remove(&i);
+        self.outlines.remove(&i);
+        self.write_errors.remove(&i);
+        self.file_ids.remove(&i);
+        self.links.remove_node(i);
     }
 
     async fn remove_notes(&mut self, file_id: FileId) {
         self.errors.remove(&file_id);
         if let Some(is) = self.ids.remove(&file_id) {
-            for i in is.iter() {
-                self.titles.remove(&i);
-                self.file_ids.remove(&i);
-                self.links.remove_node(*i);
+            for &i in &is {
+                self.forget_note(i);
             }
 
-            let removes = is.iter().map(|i| {
-                let path = self.build_subdirectory.join(format!("{}.html", i));
-                fs::remove_file(path)
-            });
+            self.remove_fragments(&is).await;
 
-            if let Err(error) = futures::future::join_all(removes)
+            let _ = self.updates.send(NoteMessage::Remove(is));
+        }
+    }
+
+    /// Deletes the fragments of notes which are gone.
+    async fn remove_fragments(&mut self, ids: &[Uuid]) {
+        let removes = ids.iter().map(|i| {
+            let path = self.build_subdirectory.join(format!("{}.html", i));
+
+            async move {
+                let path = &path;
+
+                retry(move || async move {
+                    match fs::remove_file(path).await {
+                        // Already gone, which is what we wanted
+                        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+                        result => result,
+                    }
+                })
                 .await
-                .into_iter()
-                .try_for_each(|result| result)
-            {
-                // Failed to remove fragments from build directory. This is
-                // fatal, so we need to tell the rest of the application to
-                // shutdown.
-
-                println!(
-                    "Failed to remove fragments from the build directory {}",
-                    error
-                );
-                self.cancel.cancel();
             }
+        });
 
-            let _ = self.updates.send(NoteMessage::Remove(is));
+        // A stale fragment is harmless on its own since the note is gone
+        // from the graph, so only repeated failures stop the application
+        match futures::future::join_all(removes)
+            .await
+            .into_iter()
+            .try_for_each(|result| result)
+        {
+            Ok(()) => self.failed_removals = 0,
+            Err(error) => {
+                error!(%error, "failed to remove fragments from the build directory");
+                self.failed_removals += 1;
+
+                if self.failed_removals >= MAX_CONSECUTIVE_FAILURES {
+                    error!(
+                        removals = self.failed_removals,
+                        directory = %self.build_subdirectory.display(),
+                        "repeatedly failed to remove fragments, shutting down"
+                    );
+                    self.cancel.cancel();
+                }
+            }
         }
     }
 
@@ -222,23 +347,109 @@ impl NotesServiceState {
         let initialize = Initialize {
             outgoing_links,
             titles: self.titles.clone(),
+            parents: self.parents.clone(),
+            metadata: self.metadata.clone(),
             default_note: self.default_note,
         };
 
         (initialize, self.updates.subscribe())
     }
 
+    fn note_item(&self, id: Uuid) -> Option<NoteItem> {
+        let title = self.titles.get(&id)?.clone();
+        let file_id = self.file_ids.get(&id)?;
+        let path = file_id.vpath().resolve(&self.project_directory).unwrap();
+        let metadata = self.metadata.get(&id).cloned().unwrap_or_default();
+
+        Some(NoteItem {
+            id,
+            title,
+            path,
+            metadata,
+        })
+    }
+
+    fn get_note(&mut self, id: Uuid) -> Option<NoteDetails> {
+        let item = self.note_item(id)?;
+        let outline = self.outlines.get(&id).cloned().unwrap_or_default();
+        let error = self.write_errors.get(&id).cloned();
+
+        Some(NoteDetails {
+            item,
+            outline,
+            error,
+        })
+    }
+
     fn get_notes(&mut self) -> Vec<NoteItem> {
-        let mut items = self
+        self.note_items(self.links.nodes())
+    }
+
+    fn export_graph(&mut self, metadata: bool) -> GraphExport {
+        let mut notes = self
             .links
             .nodes()
-            .map(|id| {
-                let title = self.titles.get(&id).unwrap().clone();
-                let file_id = self.file_ids.get(&id).unwrap();
-                let path = file_id.vpath().resolve(&self.project_directory).unwrap();
+            .filter_map(|id| self.note_item(id))
+            .map(|item| ExportedNote {
+                id: item.id,
+                title: item.title,
+                path: item.path,
+                metadata: metadata.then_some(item.metadata),
+            })
+            .collect::<Vec<_>>();
+        notes.sort_by(|u, v| u.title.cmp(&v.title));
 
-                NoteItem { id, title, path }
+        // Links to notes which don't exist would point at nodes missing from
+        // the export
+        let links = self
+            .links
+            .all_edges()
+            .filter(|(source, target, _)| {
+                self.titles.contains_key(source) && self.titles.contains_key(target)
             })
+            .map(|(source, target, _)| Edge { source, target })
+            .collect();
+
+        GraphExport {
+            notes,
+            links,
+            files: None,
+        }
+    }
+
+    /// The number of notes and links. Dangling link targets are nodes in the
+    /// link graph too, so notes are counted by their titles.
+    fn get_totals(&mut self) -> (usize, usize) {
+        (self.titles.len(), self.links.edge_count())
+    }
+
+    fn get_tags(&mut self) -> Vec<TagItem> {
+        self.tags
+            .iter()
+            .map(|(tag, ids)| TagItem {
+                tag: tag.clone(),
+                count: ids.len(),
+            })
+            .collect()
+    }
+
+    fn get_notes_by_tag(&mut self, tag: &str) -> Option<Vec<NoteItem>> {
+        let mut items = self
+            .tags
+            .get(tag)?
+            .iter()
+            .filter_map(|&id| self.note_item(id))
+            .collect::<Vec<_>>();
+
+        items.sort_by(|u, v| u.title.cmp(&v.title));
+
+        Some(items)
+    }
+
+    fn note_items(&self, ids: impl IntoIterator<Item = Uuid>) -> Vec<NoteItem> {
+        let mut items = ids
+            .into_iter()
+            .filter_map(|id| self.note_item(id))
             .collect::<Vec<_>>();
 
         items.sort_by(|u, v| u.title.cmp(&v.title));
@@ -246,9 +457,112 @@ impl NotesServiceState {
         items
     }
 
+    fn graph_query(&mut self, query: GraphQuery) -> Result<GraphQueryResult, GraphQueryError> {
+        // Dangling link targets are nodes too, but aren't notes
+        let check = |id: Uuid| {
+            if self.titles.contains_key(&id) {
+                Ok(id)
+            } else {
+                Err(GraphQueryError::UnknownNote(id))
+            }
+        };
+
+        match query {
+            GraphQuery::Neighborhood { id, depth } => {
+                check(id)?;
+
+                // Links are followed in both directions
+                let mut distances = HashMap::from([(id, 0)]);
+                let mut queue = VecDeque::from([id]);
+
+                while let Some(i) = queue.pop_front() {
+                    let distance = distances[&i];
+                    if distance == depth {
+                        continue;
+                    }
+
+                    let js = self
+                        .links
+                        .neighbors_directed(i, Direction::Outgoing)
+                        .chain(self.links.neighbors_directed(i, Direction::Incoming));
+                    for j in js {
+                        if let Entry::Vacant(entry) = distances.entry(j) {
+                            entry.insert(distance + 1);
+                            queue.push_back(j);
+                        }
+                    }
+                }
+
+                let mut neighbors = distances
+                    .into_iter()
+                    .filter_map(|(id, distance)| {
+                        let note = self.note_item(id)?;
+
+                        Some(NeighborItem { distance, note })
+                    })
+                    .collect::<Vec<_>>();
+                neighbors
+                    .sort_by(|u, v| (u.distance, &u.note.title).cmp(&(v.distance, &v.note.title)));
+
+                Ok(GraphQueryResult::Neighborhood(neighbors))
+            }
+            GraphQuery::ShortestPath { from, to } => {
+                check(from)?;
+                check(to)?;
+
+                let path = astar(&self.links, from, |i| i == to, |_| 1, |_| 0).map(|(_, path)| {
+                    path.into_iter()
+                        .filter_map(|id| self.note_item(id))
+                        .collect()
+                });
+
+                Ok(GraphQueryResult::Path(path))
+            }
+            GraphQuery::Orphans => {
+                let orphans = self.links.nodes().filter(|&i| {
+                    self.links
+                        .neighbors_directed(i, Direction::Incoming)
+                        .all(|j| j == i)
+                });
+
+                Ok(GraphQueryResult::Notes(self.note_items(orphans)))
+            }
+            GraphQuery::Unreachable => {
+                let mut reachable = HashSet::new();
+                if self.links.contains_node(self.default_note) {
+                    let mut dfs = Dfs::new(&self.links, self.default_note);
+                    while let Some(i) = dfs.next(&self.links) {
+                        reachable.insert(i);
+                    }
+                }
+
+                let unreachable = self.links.nodes().filter(|i| !reachable.contains(i));
+
+                Ok(GraphQueryResult::Notes(self.note_items(unreachable)))
+            }
+            GraphQuery::Clusters => {
+                let mut clusters = tarjan_scc(&self.links)
+                    .into_iter()
+                    .filter(|component| component.len() > 1)
+                    .map(|component| self.note_items(component))
+                    .collect::<Vec<_>>();
+                clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.len()));
+
+                Ok(GraphQueryResult::Clusters(clusters))
+            }
+        }
+    }
+
     fn focus_note(&mut self, id: Uuid) {
         let _ = self.updates.send(NoteMessage::Focus(id));
     }
+
+    fn set_default_note(&mut self, id: Uuid) {
+        if self.default_note != id {
+            self.default_note = id;
+            let _ = self.updates.send(NoteMessage::DefaultNote(id));
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -256,6 +570,89 @@ pub struct NoteItem {
     pub id: Uuid,
     pub title: String,
     pub path: PathBuf,
+    pub metadata: NoteMetadata,
+}
+
+/// A question about the shape of the link graph.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum GraphQuery {
+    /// Notes at most `depth` links away from a note, following links in
+    /// either direction.
+    Neighborhood { id: Uuid, depth: usize },
+    /// The shortest chain of links leading from one note to another.
+    ShortestPath { from: Uuid, to: Uuid },
+    /// Notes no other note links to.
+    Orphans,
+    /// Notes which can't be reached by following links from the default note.
+    Unreachable,
+    /// Groups of notes which can all reach each other, largest first.
+    Clusters,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NeighborItem {
+    pub distance: usize,
+    #[serde(flatten)]
+    pub note: NoteItem,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", content = "content", rename_all = "snake_case")]
+pub enum GraphQueryResult {
+    Neighborhood(Vec<NeighborItem>),
+    /// `None` if there is no path between the notes.
+    Path(Option<Vec<NoteItem>>),
+    Notes(Vec<NoteItem>),
+    Clusters(Vec<Vec<NoteItem>>),
+}
+
+#[derive(Debug, Error)]
+pub enum GraphQueryError {
+    #[error("note {0} doesn't exist")]
+    UnknownNote(Uuid),
+}
+
+/// Everything known about a single note, apart from its content.
+#[derive(Serialize, Deserialize)]
+pub struct NoteDetails {
+    #[serde(flatten)]
+    pub item: NoteItem,
+    pub outline: Vec<OutlineEntry>,
+    /// Why the note's content is missing or out of date, if it couldn't be
+    /// written to the build directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A heading within a note, linked to by an anchor id that is unique within
+/// the note.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutlineEntry {
+    /// The level relative to the note, whose own title is level 0.
+    pub level: usize,
+    pub title: String,
+    pub anchor: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TagItem {
+    pub tag: String,
+    /// The number of notes with the tag.
+    pub count: usize,
+}
+
+/// Fields a note declares with a `#metadata((..)) <note-meta>` after its
+/// heading. Keys other than the known ones are kept in `extra`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoteMetadata {
+    pub tags: Vec<String>,
+    pub aliases: Vec<String>,
+    pub created: Option<String>,
+    pub status: Option<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -263,13 +660,29 @@ pub struct NoteData {
     pub title: String,
     pub id: Uuid,
     pub links: Vec<Uuid>,
+    /// The note this one is a sub-note of.
+    pub parent: Option<Uuid>,
+    pub children: Vec<Uuid>,
+    /// Notes whose content is embedded in this one, directly or through
+    /// other transclusions.
+    pub transclusions: Vec<Uuid>,
+    pub metadata: NoteMetadata,
+    pub outline: Vec<OutlineEntry>,
+    /// Set when the note couldn't be written to the build directory.
+    pub error: Option<String>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+pub type BuildResult = Result<(Vec<String>, Vec<NoteData>), Vec<String>>;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct NoteUpdate {
     pub title: String,
     pub id: Uuid,
     pub links: Vec<Uuid>,
+    pub parent: Option<Uuid>,
+    pub children: Vec<Uuid>,
+    pub metadata: NoteMetadata,
+    pub outline: Vec<OutlineEntry>,
     pub warnings: Vec<String>,
     pub errors: Vec<String>,
 }
@@ -278,27 +691,40 @@ pub struct NoteUpdate {
 pub struct Initialize {
     pub outgoing_links: HashMap<Uuid, Vec<Uuid>>,
     pub titles: HashMap<Uuid, String>,
+    pub parents: HashMap<Uuid, Uuid>,
+    pub metadata: HashMap<Uuid, NoteMetadata>,
     pub default_note: Uuid,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum NoteMessage {
     Update(Vec<NoteUpdate>),
     Remove(Vec<Uuid>),
     Focus(Uuid),
+    DefaultNote(Uuid),
 }
 
 enum NotesMessage {
     GetNoteContent(Uuid, oneshot::Sender<Result<Option<String>, io::Error>>),
     // TODO
     // CreateNotes(FileId, Result<(Vec<String>, Vec<NoteData>), Vec<String>>),
-    UpdateNotes(Vec<(FileId, Result<(Vec<String>, Vec<NoteData>), Vec<String>>)>),
+    UpdateNotes(Vec<(FileId, BuildResult)>),
     RemoveNotes(FileId),
     SetBuildFinished,
     GetBuildFinished(oneshot::Sender<Arc<Event>>),
     Subscribe(oneshot::Sender<(Initialize, broadcast::Receiver<NoteMessage>)>),
+    GetNote(Uuid, oneshot::Sender<Option<NoteDetails>>),
     GetNotes(oneshot::Sender<Vec<NoteItem>>),
+    GetTags(oneshot::Sender<Vec<TagItem>>),
+    ExportGraph(bool, oneshot::Sender<GraphExport>),
+    GetTotals(oneshot::Sender<(usize, usize)>),
+    GraphQuery(
+        GraphQuery,
+        oneshot::Sender<Result<GraphQueryResult, GraphQueryError>>,
+    ),
+    GetNotesByTag(String, oneshot::Sender<Option<Vec<NoteItem>>>),
     Focus(Uuid),
+    SetDefaultNote(Uuid),
 }
 
 pub struct NotesService {
@@ -318,7 +744,7 @@ impl NotesService {
             //     self.state.create_notes(file_id, result);
             // }
             NotesMessage::UpdateNotes(updates) => {
-                self.state.update_notes(updates);
+                self.state.update_notes(updates).await;
             }
             NotesMessage::RemoveNotes(file_id) => {
                 self.state.remove_notes(file_id).await;
@@ -334,13 +760,40 @@ impl NotesService {
                 let result = self.state.subscribe();
                 let _ = sender.send(result);
             }
+            NotesMessage::GetNote(id, sender) => {
+                let note = self.state.get_note(id);
+                let _ = sender.send(note);
+            }
             NotesMessage::GetNotes(sender) => {
                 let notes = self.state.get_notes();
                 let _ = sender.send(notes);
             }
+            NotesMessage::GraphQuery(query, sender) => {
+                let result = self.state.graph_query(query);
+                let _ = sender.send(result);
+            }
+            NotesMessage::ExportGraph(metadata, sender) => {
+                let export = self.state.export_graph(metadata);
+                let _ = sender.send(export);
+            }
+            NotesMessage::GetTotals(sender) => {
+                let totals = self.state.get_totals();
+                let _ = sender.send(totals);
+            }
+            NotesMessage::GetTags(sender) => {
+                let tags = self.state.get_tags();
+                let _ = sender.send(tags);
+            }
+            NotesMessage::GetNotesByTag(tag, sender) => {
+                let notes = self.state.get_notes_by_tag(&tag);
+                let _ = sender.send(notes);
+            }
             NotesMessage::Focus(id) => {
                 self.state.focus_note(id);
             }
+            NotesMessage::SetDefaultNote(id) => {
+                self.state.set_default_note(id);
+            }
         }
     }
 
@@ -353,7 +806,7 @@ impl NotesService {
                     break
                 },
                 _ = self.state.cancel.cancelled() => {
-                    println!("Notes service cancel");
+                    info!("notes service cancelled");
                     self.receiver.close();
 
                     while let Some(message) = self.receiver.recv().await {
@@ -420,7 +873,7 @@ impl NotesServiceHandle {
 
     pub async fn update_notes(
         &self,
-        updates: Vec<(FileId, Result<(Vec<String>, Vec<NoteData>), Vec<String>>)>,
+        updates: Vec<(FileId, BuildResult)>,
     ) -> Result<(), NotesServiceHandleError> {
         let message = NotesMessage::UpdateNotes(updates);
         self.sender
@@ -472,6 +925,16 @@ impl NotesServiceHandle {
         receiver.await.map_err(|_| NotesServiceHandleError::Receive)
     }
 
+    pub async fn get_note(&self, id: Uuid) -> Result<Option<NoteDetails>, NotesServiceHandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(NotesMessage::GetNote(id, sender))
+            .await
+            .map_err(|_| NotesServiceHandleError::Send)?;
+
+        receiver.await.map_err(|_| NotesServiceHandleError::Receive)
+    }
+
     pub async fn get_notes(&self) -> Result<Vec<NoteItem>, NotesServiceHandleError> {
         let (sender, receiver) = oneshot::channel();
         self.sender
@@ -482,6 +945,74 @@ impl NotesServiceHandle {
         receiver.await.map_err(|_| NotesServiceHandleError::Receive)
     }
 
+    pub async fn graph_query(
+        &self,
+        query: GraphQuery,
+    ) -> Result<Result<GraphQueryResult, GraphQueryError>, NotesServiceHandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(NotesMessage::GraphQuery(query, sender))
+            .await
+            .map_err(|_| NotesServiceHandleError::Send)?;
+
+        receiver.await.map_err(|_| NotesServiceHandleError::Receive)
+    }
+
+    /// Collects the link graph, with the metadata of each note if
+    /// `metadata` is set.
+    pub async fn export_graph(
+        &self,
+        metadata: bool,
+    ) -> Result<GraphExport, NotesServiceHandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(NotesMessage::ExportGraph(metadata, sender))
+            .await
+            .map_err(|_| NotesServiceHandleError::Send)?;
+
+        receiver.await.map_err(|_| NotesServiceHandleError::Receive)
+    }
+
+    /// The number of notes and links between them.
+    pub async fn get_totals(&self) -> Result<(usize, usize), NotesServiceHandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(NotesMessage::GetTotals(sender))
+            .await
+            .map_err(|_| NotesServiceHandleError::Send)?;
+
+        receiver.await.map_err(|_| NotesServiceHandleError::Receive)
+    }
+
+    /// The number of messages waiting to be handled.
+    pub fn queue_depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    pub async fn get_tags(&self) -> Result<Vec<TagItem>, NotesServiceHandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(NotesMessage::GetTags(sender))
+            .await
+            .map_err(|_| NotesServiceHandleError::Send)?;
+
+        receiver.await.map_err(|_| NotesServiceHandleError::Receive)
+    }
+
+    /// Lists the notes with a tag, or `None` if no note has it.
+    pub async fn get_notes_by_tag(
+        &self,
+        tag: String,
+    ) -> Result<Option<Vec<NoteItem>>, NotesServiceHandleError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(NotesMessage::GetNotesByTag(tag, sender))
+            .await
+            .map_err(|_| NotesServiceHandleError::Send)?;
+
+        receiver.await.map_err(|_| NotesServiceHandleError::Receive)
+    }
+
     pub async fn focus_note(&self, id: Uuid) -> Result<(), NotesServiceHandleError> {
         self.sender
             .send(NotesMessage::Focus(id))
@@ -489,6 +1020,14 @@ impl NotesServiceHandle {
             .map_err(|_| NotesServiceHandleError::Send)
     }
 
+    /// Changes the note clients open first, and tells connected clients.
+    pub async fn set_default_note(&self, id: Uuid) -> Result<(), NotesServiceHandleError> {
+        self.sender
+            .send(NotesMessage::SetDefaultNote(id))
+            .await
+            .map_err(|_| NotesServiceHandleError::Send)
+    }
+
     pub fn build(
         cancel: CancellationToken,
         build_subdirectory: PathBuf,
@@ -501,12 +1040,18 @@ impl NotesServiceHandle {
         let (updates, _) = broadcast::channel(BUFFER_SIZE);
         let state = NotesServiceState {
             cancel,
+            failed_removals: 0,
             build_subdirectory,
             project_directory,
             default_note,
             links: DiGraphMap::default(),
             ids: HashMap::default(),
             titles: HashMap::default(),
+            parents: HashMap::default(),
+            metadata: HashMap::default(),
+            tags: BTreeMap::default(),
+            outlines: HashMap::default(),
+            write_errors: HashMap::default(),
             file_ids: HashMap::default(),
             errors: HashMap::default(),
             build_finished_event: Event::new(),
@@ -518,3 +1063,275 @@ impl NotesServiceHandle {
         (handle, service)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use typst::syntax::VirtualPath;
+
+    use super::*;
+
+    fn state() -> NotesServiceState {
+        let (_, service) = NotesServiceHandle::build(
+            CancellationToken::new(),
+            PathBuf::from("/project/build"),
+            PathBuf::from("/project"),
+            Uuid::nil(),
+        );
+
+        service.state
+    }
+
+    fn file(path: &str) -> FileId {
+        FileId::new(None, VirtualPath::new(path))
+    }
+
+    fn note(id: u128, title: &str, links: &[u128]) -> NoteData {
+        NoteData {
+            title: title.into(),
+            id: Uuid::from_u128(id),
+            links: links.iter().copied().map(Uuid::from_u128).collect(),
+            parent: None,
+            children: Vec::new(),
+            transclusions: Vec::new(),
+            metadata: NoteMetadata::default(),
+            outline: Vec::new(),
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn export_skips_dangling_links() {
+        let mut state = state();
+        state
+            .update_notes(vec![(
+                file("notes/a.typ"),
+                Ok((Vec::new(), vec![note(1, "A", &[2, 3]), note(2, "B", &[])])),
+            )])
+            .await;
+
+        let export = state.export_graph(false);
+
+        assert_eq!(export.notes.len(), 2);
+        assert_eq!(export.links.len(), 1);
+        assert_eq!(export.links[0].source, Uuid::from_u128(1));
+        assert_eq!(export.links[0].target, Uuid::from_u128(2));
+    }
+
+    #[tokio::test]
+    async fn notes_removed_from_a_file_are_forgotten() {
+        let mut state = state();
+        let mut tagged = note(2, "B", &[]);
+        tagged.metadata.tags = vec!["draft".into()];
+        state
+            .update_notes(vec![(
+                file("notes/a.typ"),
+                Ok((Vec::new(), vec![note(1, "A", &[2]), tagged])),
+            )])
+            .await;
+        assert_eq!(state.get_tags().len(), 1);
+
+        let mut updates = state.updates.subscribe();
+        state
+            .update_notes(vec![(
+                file("notes/a.typ"),
+                Ok((Vec::new(), vec![note(1, "A", &[2])])),
+            )])
+            .await;
+
+        assert!(state.get_tags().is_empty());
+        assert!(state.get_notes_by_tag("draft").is_none());
+        assert_eq!(state.get_totals(), (1, 0));
+        assert!(state.get_note(Uuid::from_u128(2)).is_none());
+        assert_eq!(
+            updates.try_recv().unwrap(),
+            NoteMessage::Remove(vec![Uuid::from_u128(2)])
+        );
+    }
+
+    /// 1 links to 2 and a missing note, 2 and 3 link to each other and 4 only
+    /// links to itself.
+    async fn linked_state() -> NotesServiceState {
+        let mut state = state();
+        state.default_note = Uuid::from_u128(1);
+        state
+            .update_notes(vec![(
+                file("notes/a.typ"),
+                Ok((
+                    Vec::new(),
+                    vec![
+                        note(1, "One", &[2, 9]),
+                        note(2, "Two", &[3]),
+                        note(3, "Three", &[2]),
+                        note(4, "Four", &[4]),
+                    ],
+                )),
+            )])
+            .await;
+
+        state
+    }
+
+    fn ids(items: &[NoteItem]) -> Vec<u128> {
+        items.iter().map(|item| item.id.as_u128()).collect()
+    }
+
+    #[tokio::test]
+    async fn neighborhoods_follow_links_both_ways() {
+        let mut state = linked_state().await;
+        let mut neighborhood = |id, depth| {
+            let query = GraphQuery::Neighborhood {
+                id: Uuid::from_u128(id),
+                depth,
+            };
+            let Ok(GraphQueryResult::Neighborhood(neighbors)) = state.graph_query(query) else {
+                panic!("expected a neighborhood");
+            };
+
+            neighbors
+                .into_iter()
+                .map(|neighbor| (neighbor.distance, neighbor.note.id.as_u128()))
+                .collect::<Vec<_>>()
+        };
+
+        // The missing note isn't listed
+        assert_eq!(neighborhood(1, 1), [(0, 1), (1, 2)]);
+        assert_eq!(neighborhood(3, 1), [(0, 3), (1, 2)]);
+        assert_eq!(neighborhood(3, 5), [(0, 3), (1, 2), (2, 1)]);
+        assert_eq!(neighborhood(4, 0), [(0, 4)]);
+    }
+
+    #[tokio::test]
+    async fn shortest_paths_follow_links() {
+        let mut state = linked_state().await;
+        let mut path = |from, to| {
+            let query = GraphQuery::ShortestPath {
+                from: Uuid::from_u128(from),
+                to: Uuid::from_u128(to),
+            };
+
+            match state.graph_query(query) {
+                Ok(GraphQueryResult::Path(path)) => Ok(path.map(|path| ids(&path))),
+                Ok(_) => panic!("expected a path"),
+                Err(error) => Err(error.to_string()),
+            }
+        };
+
+        assert_eq!(path(1, 3), Ok(Some(vec![1, 2, 3])));
+        assert_eq!(path(3, 1), Ok(None));
+        assert_eq!(path(1, 1), Ok(Some(vec![1])));
+        for missing in [8, 9] {
+            assert_eq!(
+                path(1, missing),
+                Err(format!("note {} doesn't exist", Uuid::from_u128(missing)))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn orphans_unreachable_notes_and_clusters() {
+        let mut state = linked_state().await;
+        let mut notes = |query| match state.graph_query(query) {
+            Ok(GraphQueryResult::Notes(notes)) => ids(&notes),
+            Ok(GraphQueryResult::Clusters(clusters)) => {
+                clusters.iter().flat_map(|cluster| ids(cluster)).collect()
+            }
+            _ => panic!("expected notes"),
+        };
+
+        // Sorted by title, linking to itself doesn't count
+        assert_eq!(notes(GraphQuery::Orphans), [4, 1]);
+        assert_eq!(notes(GraphQuery::Unreachable), [4]);
+        assert_eq!(notes(GraphQuery::Clusters), [3, 2]);
+    }
+
+    /// Builds a note in its own file and removes the file again. The removal
+    /// fails if `stuck` is set, since the fragment is a directory.
+    async fn build_and_remove(state: &mut NotesServiceState, id: u128, stuck: bool) {
+        let file = file(&format!("notes/{id}.typ"));
+        let fragment = state
+            .build_subdirectory
+            .join(format!("{}.html", Uuid::from_u128(id)));
+        if stuck {
+            std::fs::create_dir_all(&fragment).unwrap();
+        } else {
+            std::fs::write(&fragment, "").unwrap();
+        }
+
+        state
+            .update_notes(vec![(file, Ok((Vec::new(), vec![note(id, "A", &[])])))])
+            .await;
+        state.remove_notes(file).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeated_removal_failures_stop_the_service() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let mut state = state();
+        state.build_subdirectory = directory.path().to_owned();
+
+        for id in 1..MAX_CONSECUTIVE_FAILURES as u128 {
+            build_and_remove(&mut state, id, true).await;
+        }
+        // A success in between starts the count over
+        build_and_remove(&mut state, 100, false).await;
+        for id in 1..MAX_CONSECUTIVE_FAILURES as u128 {
+            build_and_remove(&mut state, 200 + id, true).await;
+        }
+        assert!(!state.cancel.is_cancelled());
+
+        build_and_remove(&mut state, 300, true).await;
+        assert!(state.cancel.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn notes_skip_dangling_link_targets() {
+        let mut state = state();
+        state
+            .update_notes(vec![(
+                file("notes/a.typ"),
+                Ok((Vec::new(), vec![note(2, "B", &[9]), note(1, "A", &[2])])),
+            )])
+            .await;
+
+        assert_eq!(ids(&state.get_notes()), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn fragments_of_removed_notes_are_deleted() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let mut state = state();
+        state.build_subdirectory = directory.path().to_owned();
+        let fragment = |id: u128| {
+            directory
+                .path()
+                .join(format!("{}.html", Uuid::from_u128(id)))
+        };
+        for id in [1, 2] {
+            std::fs::write(fragment(id), "<article></article>").unwrap();
+        }
+        state
+            .update_notes(vec![(
+                file("notes/a.typ"),
+                Ok((Vec::new(), vec![note(1, "A", &[]), note(2, "B", &[])])),
+            )])
+            .await;
+
+        // B is removed from the file while A starts linking to it
+        state
+            .update_notes(vec![(
+                file("notes/a.typ"),
+                Ok((Vec::new(), vec![note(1, "A", &[2])])),
+            )])
+            .await;
+
+        assert!(fragment(1).exists());
+        assert!(!fragment(2).exists());
+        assert!(
+            state
+                .get_note_content(Uuid::from_u128(2))
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
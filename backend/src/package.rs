@@ -1,12 +1,21 @@
-use std::{fmt::Debug, fs, io, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use bytes::{Buf, Bytes};
+use directories::ProjectDirs;
 use http::{Method, StatusCode, Uri, uri::InvalidUri};
 use http_body::Body;
 use http_body_util::BodyExt;
 use hyper::body::Incoming;
 use hyper_util::client::legacy::{Client, connect::Connect};
 use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
 use serde::Deserialize;
 use tempfile::TempDir;
 use thiserror::Error;
@@ -17,6 +26,9 @@ use typst::{
     ecow::{EcoString, eco_format},
     syntax::package::{PackageSpec, PackageVersion},
 };
+use walkdir::WalkDir;
+
+use crate::metrics::Metrics;
 
 pub const DEFAULT_REGISTRY: &str = "https://packages.typst.org";
 pub const DEFAULT_NAMESPACE: &str = "preview";
@@ -321,11 +333,31 @@ impl From<GetIndexServiceError<hyper_util::client::legacy::Error, hyper::Error,
     }
 }
 
-#[derive(Clone, Debug)]
+/// How long a failed download is remembered before the registry is asked
+/// again.
+const FAILED_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A download of a single package, shared by every caller that asks for the
+/// same specification while it is in flight.
+#[derive(Debug, Default)]
+struct Download {
+    result: OnceCell<(PackageResult<()>, Instant)>,
+}
+
+impl Download {
+    fn is_expired(&self) -> bool {
+        self.result.get().is_some_and(|(result, finished)| {
+            result.is_err() && finished.elapsed() >= FAILED_DOWNLOAD_TIMEOUT
+        })
+    }
+}
+
+#[derive(Debug)]
 struct PackageStorageState {
     cache_directory: PathBuf,
     data_directory: PathBuf,
     index: OnceCell<Vec<Package>>,
+    downloads: Mutex<HashMap<PackageSpec, Arc<Download>>>,
 }
 
 #[derive(Clone)]
@@ -333,6 +365,7 @@ pub struct PackageStorage<S> {
     state: Arc<PackageStorageState>,
     handle: Handle,
     service: S,
+    metrics: Arc<Metrics>,
 }
 
 impl<S> PackageStorage<S>
@@ -347,15 +380,18 @@ where
         data_directory: PathBuf,
         handle: Handle,
         service: S,
+        metrics: Arc<Metrics>,
     ) -> Self {
         Self {
             state: Arc::new(PackageStorageState {
                 cache_directory,
                 data_directory,
                 index: OnceCell::new(),
+                downloads: Mutex::new(HashMap::new()),
             }),
             handle,
             service,
+            metrics,
         }
     }
 
@@ -398,6 +434,48 @@ where
         }
     }
 
+    /// Downloads a package at most once at a time. Concurrent callers asking
+    /// for the same specification block until the first download finishes and
+    /// share its result. Failures are kept around for
+    /// [`FAILED_DOWNLOAD_TIMEOUT`] so that every compile doesn't hit the
+    /// registry again.
+    fn download_package_once(&self, specification: &PackageSpec) -> PackageResult<()> {
+        let download = {
+            let mut downloads = self.state.downloads.lock();
+
+            if downloads
+                .get(specification)
+                .is_some_and(|download| download.is_expired())
+            {
+                downloads.remove(specification);
+            }
+
+            downloads.entry(specification.clone()).or_default().clone()
+        };
+
+        let (result, _) = download.result.get_or_init(|| {
+            let result = self.download_package(specification);
+            self.metrics.package_downloaded(result.is_ok());
+
+            (result, Instant::now())
+        });
+
+        if result.is_ok() {
+            // The package is on disk now, so later callers find it without
+            // going through the download table.
+            let mut downloads = self.state.downloads.lock();
+
+            if downloads
+                .get(specification)
+                .is_some_and(|other| Arc::ptr_eq(other, &download))
+            {
+                downloads.remove(specification);
+            }
+        }
+
+        result.clone()
+    }
+
     pub fn prepare_package(&self, specification: &PackageSpec) -> PackageResult<PathBuf> {
         let subdirectory = format!(
             "{}/{}/{}",
@@ -414,7 +492,7 @@ where
             return Ok(directory);
         }
 
-        self.download_package(specification)?;
+        self.download_package_once(specification)?;
         if directory.exists() {
             return Ok(directory);
         }
@@ -422,3 +500,340 @@ where
         Err(PackageError::NotFound(specification.clone()))
     }
 }
+
+/// Written to the data directory once its downloads were moved, so that
+/// packages put there afterwards are left alone.
+const MIGRATED_MARKER: &str = ".downloads-migrated";
+
+/// Moves packages downloaded by earlier versions of phelps out of the data
+/// directory and into the cache directory.
+///
+/// Earlier versions always downloaded into the default data directory, so a
+/// configured one is left alone. Downloads only ever come from the
+/// [`DEFAULT_NAMESPACE`], so anything in other namespaces of the data
+/// directory is left alone as a local package.
+pub fn migrate_downloaded_packages(
+    data_directory: &Path,
+    cache_directory: &Path,
+) -> Result<(), io::Error> {
+    match ProjectDirs::from("", "", "phelps") {
+        Some(directories) if directories.data_dir() == data_directory => {
+            migrate_packages(data_directory, cache_directory)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn migrate_packages(data_directory: &Path, cache_directory: &Path) -> Result<(), io::Error> {
+    let marker = data_directory.join(MIGRATED_MARKER);
+    let source = data_directory.join(DEFAULT_NAMESPACE);
+    let destination = cache_directory.join(DEFAULT_NAMESPACE);
+
+    if source == destination || marker.exists() {
+        return Ok(());
+    }
+
+    if source.is_dir() {
+        for name in fs::read_dir(&source)? {
+            let name = name?;
+            if !name.file_type()?.is_dir() {
+                continue;
+            }
+
+            for version in fs::read_dir(name.path())? {
+                let version = version?;
+                let target = destination.join(name.file_name()).join(version.file_name());
+
+                if target.exists() {
+                    // Already cached, the copy in the data directory is redundant.
+                    fs::remove_dir_all(version.path())?;
+                } else {
+                    fs::create_dir_all(target.parent().unwrap())?;
+                    move_directory(&version.path(), &target)?;
+                }
+            }
+
+            // Only succeeds if nothing else was left behind.
+            let _ = fs::remove_dir(name.path());
+        }
+
+        let _ = fs::remove_dir(&source);
+    }
+
+    fs::create_dir_all(data_directory)?;
+    fs::write(marker, "")
+}
+
+fn move_directory(source: &Path, destination: &Path) -> Result<(), io::Error> {
+    if fs::rename(source, destination).is_ok() {
+        return Ok(());
+    }
+
+    // Renaming fails across file systems, so fall back to copying.
+    copy_directory(source, destination)?;
+
+    fs::remove_dir_all(source)
+}
+
+/// Copies a directory next to the destination first, and only moves the
+/// copy into place once it is complete. A failure halfway never leaves a
+/// partial copy at the destination.
+fn copy_directory(source: &Path, destination: &Path) -> Result<(), io::Error> {
+    let parent = destination
+        .parent()
+        .ok_or_else(|| io::Error::other("destination has no parent directory"))?;
+    let temporary = tempfile::Builder::new()
+        .prefix(".partial-")
+        .tempdir_in(parent)?;
+
+    for entry in WalkDir::new(source) {
+        let entry = entry?;
+        let target = temporary
+            .path()
+            .join(entry.path().strip_prefix(source).unwrap());
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+
+    fs::rename(temporary.path(), destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use flate2::{Compression, write::GzEncoder};
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    /// A registry serving at most one package, counting the downloads.
+    #[derive(Clone, Default)]
+    struct CountingPackages {
+        archive: Option<Bytes>,
+        downloads: Arc<AtomicUsize>,
+    }
+
+    impl CountingPackages {
+        fn with_package() -> Self {
+            let contents = "#let answer = 42";
+            let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive
+                .append_data(&mut header, "lib.typ", contents.as_bytes())
+                .unwrap();
+
+            let mut encoder = archive.into_inner().unwrap();
+            encoder.flush().unwrap();
+
+            Self {
+                archive: Some(Bytes::from(encoder.finish().unwrap())),
+                downloads: Arc::default(),
+            }
+        }
+
+        fn downloads(&self) -> usize {
+            self.downloads.load(Ordering::SeqCst)
+        }
+    }
+
+    impl PackageService for CountingPackages {
+        type GetIndexServiceError = PackageError;
+
+        async fn get_index(&self) -> Result<Vec<Package>, Self::GetIndexServiceError> {
+            Ok(Vec::new())
+        }
+
+        type GetPackageServiceError = PackageError;
+        type GetPackageBuffer = Bytes;
+
+        async fn get_package(
+            &self,
+            _specification: PackageSpec,
+        ) -> Result<Result<Self::GetPackageBuffer, GetPackageError>, Self::GetPackageServiceError>
+        {
+            self.downloads.fetch_add(1, Ordering::SeqCst);
+            // Long enough for every caller to ask while this is in flight
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            Ok(self.archive.clone().ok_or(GetPackageError::NotFound))
+        }
+    }
+
+    fn specification() -> PackageSpec {
+        "@preview/answer:0.1.0".parse().unwrap()
+    }
+
+    fn storage(
+        runtime: &Runtime,
+        directory: &TempDir,
+        service: CountingPackages,
+    ) -> PackageStorage<CountingPackages> {
+        PackageStorage::new(
+            directory.path().join("cache"),
+            directory.path().join("data"),
+            runtime.handle().clone(),
+            service,
+            Arc::default(),
+        )
+    }
+
+    #[test]
+    fn concurrent_callers_share_a_download() {
+        let runtime = Runtime::new().unwrap();
+        let directory = TempDir::new().unwrap();
+        let service = CountingPackages::with_package();
+        let storage = storage(&runtime, &directory, service.clone());
+
+        let directories: Vec<PathBuf> = thread::scope(|scope| {
+            let callers: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| storage.prepare_package(&specification())))
+                .collect();
+
+            callers
+                .into_iter()
+                .map(|caller| caller.join().unwrap().unwrap())
+                .collect()
+        });
+
+        assert_eq!(service.downloads(), 1);
+        for package in directories {
+            assert_eq!(package, directory.path().join("cache/preview/answer/0.1.0"));
+            assert!(package.join("lib.typ").is_file());
+        }
+
+        // On disk now, so the registry isn't asked again
+        storage.prepare_package(&specification()).unwrap();
+        assert_eq!(service.downloads(), 1);
+    }
+
+    #[test]
+    fn failed_downloads_are_remembered() {
+        let runtime = Runtime::new().unwrap();
+        let directory = TempDir::new().unwrap();
+        let service = CountingPackages::default();
+        let storage = storage(&runtime, &directory, service.clone());
+
+        assert!(storage.prepare_package(&specification()).is_err());
+        assert!(storage.prepare_package(&specification()).is_err());
+        assert_eq!(service.downloads(), 1);
+    }
+
+    #[test]
+    fn failures_expire() {
+        let finished = Instant::now() - FAILED_DOWNLOAD_TIMEOUT;
+        let failed = Download {
+            result: OnceCell::with_value((Err(PackageError::NotFound(specification())), finished)),
+        };
+        let succeeded = Download {
+            result: OnceCell::with_value((Ok(()), finished)),
+        };
+
+        assert!(failed.is_expired());
+        assert!(!succeeded.is_expired());
+        assert!(!Download::default().is_expired());
+    }
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn downloads_move_to_the_cache() {
+        let directory = TempDir::new().unwrap();
+        let (data, cache) = (
+            directory.path().join("data"),
+            directory.path().join("cache"),
+        );
+        write(&data.join("preview/answer/0.1.0/lib.typ"), "moved");
+        write(&data.join("preview/answer/0.2.0/lib.typ"), "redundant");
+        write(&cache.join("preview/answer/0.2.0/lib.typ"), "cached");
+        write(&data.join("local/mine/0.1.0/lib.typ"), "local");
+
+        migrate_packages(&data, &cache).unwrap();
+
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(cache.join("preview/answer/0.1.0/lib.typ")), "moved");
+        assert_eq!(read(cache.join("preview/answer/0.2.0/lib.typ")), "cached");
+        assert_eq!(read(data.join("local/mine/0.1.0/lib.typ")), "local");
+        assert!(!data.join("preview").exists());
+
+        // Migrated once, a package put there later stays
+        write(&data.join("preview/answer/0.3.0/lib.typ"), "later");
+        migrate_packages(&data, &cache).unwrap();
+        assert_eq!(read(data.join("preview/answer/0.3.0/lib.typ")), "later");
+    }
+
+    #[test]
+    fn configured_data_directories_are_left_alone() {
+        let directory = TempDir::new().unwrap();
+        let (data, cache) = (
+            directory.path().join("data"),
+            directory.path().join("cache"),
+        );
+        write(&data.join("preview/answer/0.1.0/lib.typ"), "");
+
+        migrate_downloaded_packages(&data, &cache).unwrap();
+
+        assert!(data.join("preview/answer/0.1.0/lib.typ").is_file());
+        assert!(!cache.exists());
+    }
+
+    #[test]
+    fn migrating_into_itself_does_nothing() {
+        let directory = TempDir::new().unwrap();
+        write(&directory.path().join("preview/answer/0.1.0/lib.typ"), "");
+
+        migrate_packages(directory.path(), directory.path()).unwrap();
+
+        assert!(
+            directory
+                .path()
+                .join("preview/answer/0.1.0/lib.typ")
+                .is_file()
+        );
+    }
+
+    #[test]
+    fn copies_only_appear_once_complete() {
+        let directory = TempDir::new().unwrap();
+        let (source, destination) = (directory.path().join("a"), directory.path().join("b"));
+        write(&source.join("lib.typ"), "lib");
+        write(&source.join("nested/util.typ"), "util");
+
+        copy_directory(&source, &destination).unwrap();
+
+        for (path, contents) in [("lib.typ", "lib"), ("nested/util.typ", "util")] {
+            assert_eq!(
+                fs::read_to_string(destination.join(path)).unwrap(),
+                contents
+            );
+        }
+
+        // Copying a dangling link fails partway
+        let destination = directory.path().join("c");
+        std::os::unix::fs::symlink(directory.path().join("missing"), source.join("link")).unwrap();
+
+        assert!(copy_directory(&source, &destination).is_err());
+        assert!(!destination.exists());
+        // Nothing partial is left next to it either
+        let mut entries: Vec<_> = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, ["a", "b"]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::{io, time::Duration};
+
+use tracing::warn;
+
+const ATTEMPTS: u32 = 4;
+const INITIAL_DELAY: Duration = Duration::from_millis(100);
+
+/// How many operations may fail in a row, even after retrying, before the
+/// failure is considered persistent and the application stops.
+pub const MAX_CONSECUTIVE_FAILURES: usize = 5;
+
+/// Runs an IO operation until it succeeds, waiting four times as long after
+/// each failure. Gives up with the last error after a few attempts.
+pub async fn retry<T, F, Fut>(mut operation: F) -> Result<T, io::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, io::Error>>,
+{
+    let mut delay = INITIAL_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(error) if attempt < ATTEMPTS => {
+                warn!(%error, attempt, "IO operation failed, retrying");
+
+                tokio::time::sleep(delay).await;
+                delay *= 4;
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use tokio::time::Instant;
+
+    use super::*;
+
+    /// Runs an operation failing `failures` times, returning the result, the
+    /// number of attempts and the time spent waiting.
+    async fn failing(failures: u32) -> (Result<u32, io::Error>, u32, Duration) {
+        let attempts = Cell::new(0);
+        let start = Instant::now();
+
+        let result = retry(|| {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+
+            async move {
+                if attempt <= failures {
+                    Err(io::Error::other(format!("attempt {attempt}")))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await;
+
+        (result, attempts.get(), start.elapsed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn succeeds_without_waiting() {
+        let (result, attempts, waited) = failing(0).await;
+
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(attempts, 1);
+        assert_eq!(waited, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backs_off_between_attempts() {
+        let (result, attempts, waited) = failing(2).await;
+
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(attempts, 3);
+        assert_eq!(waited, Duration::from_millis(100 + 400));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_with_the_last_error() {
+        let (result, attempts, waited) = failing(u32::MAX).await;
+
+        assert_eq!(result.unwrap_err().to_string(), "attempt 4");
+        assert_eq!(attempts, ATTEMPTS);
+        assert_eq!(waited, Duration::from_millis(100 + 400 + 1600));
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Path, PathBuf},
+};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use tracing::warn;
+
+use crate::{config::SourceGlobs, file_source::FileSource};
+
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// Decides which files are notes, and which paths the watcher can disregard.
+#[derive(Clone, Debug)]
+pub struct SourceFilter {
+    project_directory: PathBuf,
+    source_directories: Vec<PathBuf>,
+    build_subdirectory: PathBuf,
+    globs: SourceGlobs,
+    /// Rules from each directory with ignore files, parents before their
+    /// children.
+    ignores: Vec<(PathBuf, Gitignore)>,
+}
+
+impl SourceFilter {
+    /// Walks the whole project for ignore files, so this blocks.
+    pub fn new(
+        files: &dyn FileSource,
+        project_directory: PathBuf,
+        source_directories: Vec<PathBuf>,
+        build_subdirectory: PathBuf,
+        globs: SourceGlobs,
+    ) -> Self {
+        let mut filter = Self {
+            project_directory,
+            source_directories,
+            build_subdirectory,
+            globs,
+            ignores: Vec::new(),
+        };
+
+        // `.git/info/exclude` is never walked, but belongs to the project
+        // directory
+        let mut directories = BTreeSet::from([filter.project_directory.clone()]);
+        directories.extend(
+            files
+                .walk(&filter.project_directory, &|directory| {
+                    !filter.is_always_ignored(directory)
+                })
+                .into_iter()
+                .filter(|path| {
+                    path.file_name()
+                        .is_some_and(|name| IGNORE_FILES.iter().any(|file| name == *file))
+                })
+                .filter_map(|path| path.parent().map(Path::to_owned)),
+        );
+        // Parents sort before their children
+        for directory in directories {
+            filter.load(files, &directory);
+        }
+
+        filter
+    }
+
+    /// Whether the file is a note source: a Typst file in one of the source
+    /// directories which isn't ignored or excluded.
+    pub fn is_source(&self, path: &Path) -> bool {
+        path.extension().is_some_and(|e| e == "typ")
+            && self
+                .source_directories
+                .iter()
+                .any(|directory| path.starts_with(directory))
+            && !self.is_ignored(path, false)
+    }
+
+    /// Whether the path is phelps' own output, version control data, ignored
+    /// by an ignore file or excluded by the config.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if self.is_always_ignored(path) {
+            return true;
+        }
+
+        let Ok(relative) = path.strip_prefix(&self.project_directory) else {
+            return false;
+        };
+        if !is_dir && !self.globs.is_match(relative) {
+            return true;
+        }
+
+        // Rules closer to the file take precedence
+        self.ignores
+            .iter()
+            .rev()
+            .filter(|(directory, _)| path.starts_with(directory))
+            .map(|(_, ignore)| ignore.matched_path_or_any_parents(path, is_dir))
+            .find(|matched| !matched.is_none())
+            .is_some_and(|matched| matched.is_ignore())
+    }
+
+    /// Reloads the rules if the path is an ignore file. Returns whether it was.
+    pub fn reload(&mut self, files: &dyn FileSource, path: &Path) -> bool {
+        let directory = if path.ends_with(".git/info/exclude") {
+            self.project_directory.clone()
+        } else if path
+            .file_name()
+            .is_some_and(|name| IGNORE_FILES.iter().any(|file| name == *file))
+            && let Some(directory) = path.parent()
+        {
+            directory.to_owned()
+        } else {
+            return false;
+        };
+
+        self.ignores.retain(|(other, _)| *other != directory);
+        self.load(files, &directory);
+
+        true
+    }
+
+    fn is_always_ignored(&self, path: &Path) -> bool {
+        path.starts_with(&self.build_subdirectory)
+            || path
+                .strip_prefix(&self.project_directory)
+                .is_ok_and(|relative| relative.components().any(|c| c.as_os_str() == ".git"))
+    }
+
+    fn load(&mut self, files: &dyn FileSource, directory: &Path) {
+        let mut builder = GitignoreBuilder::new(directory);
+        let mut paths: Vec<PathBuf> = IGNORE_FILES
+            .iter()
+            .map(|file| directory.join(file))
+            .collect();
+        if directory == self.project_directory {
+            paths.push(directory.join(".git/info/exclude"));
+        }
+
+        let mut found = false;
+        for path in paths {
+            let contents = match files.read(&path) {
+                Ok(contents) => contents,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => {
+                    warn!(%error, path = %path.display(), "couldn't read ignore file");
+
+                    continue;
+                }
+            };
+            found = true;
+
+            for line in String::from_utf8_lossy(&contents).lines() {
+                if let Err(error) = builder.add_line(Some(path.clone()), line) {
+                    warn!(%error, path = %path.display(), "invalid ignore rule");
+                }
+            }
+        }
+        if !found {
+            return;
+        }
+
+        match builder.build() {
+            Ok(ignore) => {
+                // Keep parents before their children
+                let index = self
+                    .ignores
+                    .iter()
+                    .position(|(other, _)| other.starts_with(directory))
+                    .unwrap_or(self.ignores.len());
+                self.ignores.insert(index, (directory.to_owned(), ignore));
+            }
+            Err(error) => {
+                warn!(%error, directory = %directory.display(), "couldn't load ignore rules");
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     collections::{HashMap, HashSet},
-    fs, mem,
+    fs, io, mem,
     ops::DerefMut,
     path::{Path, PathBuf},
     sync::Arc,
@@ -8,19 +8,25 @@ use std::{
 
 use bytes::Buf;
 use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use time::{UtcDateTime, UtcOffset};
+use tokio_util::sync::CancellationToken;
 use typst::{
     Feature, Features, Library, LibraryExt, World,
     diag::{FileError, FileResult, PackageError},
-    foundations::{Bytes, Datetime},
-    syntax::{FileId, Source},
+    foundations::{Bytes, Datetime, Dict, IntoValue},
+    syntax::{FileId, Lines, Source},
     text::{Font, FontBook},
     utils::LazyHash,
 };
-use typst_kit::fonts::{FontSearcher, FontSlot};
+use typst_kit::fonts::{FontSearcher, FontSlot, Fonts};
 
-use crate::package::{PackageService, PackageStorage};
+use crate::{
+    config::{Config, FontConfig},
+    file_source::FileSource,
+    package::{PackageService, PackageStorage},
+};
 
 #[derive(Debug)]
 pub struct Resources {
@@ -28,13 +34,29 @@ pub struct Resources {
     library: LazyHash<Library>,
     book: LazyHash<FontBook>,
     fonts: Vec<FontSlot>,
+    creation_timestamp: Option<UtcDateTime>,
+}
+
+pub fn search_fonts(config: &FontConfig) -> Fonts {
+    FontSearcher::new()
+        .include_system_fonts(config.system)
+        .include_embedded_fonts(config.embedded)
+        .search_with(&config.directories)
 }
 
 impl Resources {
-    pub fn new(root: PathBuf) -> Self {
-        let fonts = FontSearcher::new().include_system_fonts(true).search();
+    /// When `creation_timestamp` is set, every compile sees it as the current
+    /// date instead of the wall clock time.
+    pub fn new(
+        root: PathBuf,
+        fonts: &FontConfig,
+        inputs: Dict,
+        creation_timestamp: Option<UtcDateTime>,
+    ) -> Self {
+        let fonts = search_fonts(fonts);
         let library = Library::builder()
             .with_features(Features::from_iter([Feature::Html]))
+            .with_inputs(inputs)
             .build();
 
         Self {
@@ -42,8 +64,25 @@ impl Resources {
             library: LazyHash::new(library),
             book: LazyHash::new(fonts.book),
             fonts: fonts.fonts,
+            creation_timestamp,
         }
     }
+
+    /// Searches the fonts and sets the inputs the config asks for.
+    pub fn from_config(config: &Config) -> Self {
+        let inputs = config
+            .inputs
+            .iter()
+            .map(|(key, value)| (key.as_str().into(), value.as_str().into_value()))
+            .collect();
+
+        Self::new(
+            config.project_directory.clone(),
+            &config.fonts,
+            inputs,
+            config.creation_timestamp,
+        )
+    }
 }
 
 struct State {
@@ -63,10 +102,121 @@ pub enum SystemWorldCreationError {
     PathOutsideRoot,
 }
 
+/// A position in a text buffer, counted like the language server protocol
+/// does: zero based lines and UTF-16 code units within the line.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TextPosition {
+    pub line: usize,
+    pub character: usize,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TextRange {
+    pub start: TextPosition,
+    pub end: TextPosition,
+}
+
+/// A change to an editor buffer. Without a range, `text` replaces the whole
+/// buffer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TextChange {
+    #[serde(default)]
+    pub range: Option<TextRange>,
+    pub text: String,
+}
+
+#[derive(Debug, Error)]
+pub enum OverlayError {
+    #[error("path outside project root")]
+    PathOutsideRoot,
+    #[error("range outside of the buffer")]
+    InvalidRange,
+    #[error("couldn't read file to apply incremental change: {0}")]
+    Read(std::io::Error),
+}
+
+/// Contents of files open in an editor, which reads prefer over the contents
+/// on disk. This lets notes be previewed before they are saved.
+#[derive(Clone, Default)]
+pub struct Overlay(Arc<Mutex<HashMap<FileId, Lines<String>>>>);
+
+impl Overlay {
+    pub fn contains(&self, id: FileId) -> bool {
+        self.0.lock().contains_key(&id)
+    }
+
+    pub fn get(&self, id: FileId) -> Option<Vec<u8>> {
+        self.0
+            .lock()
+            .get(&id)
+            .map(|lines| lines.text().as_bytes().to_vec())
+    }
+
+    /// Applies `changes` in order. Files not yet in the overlay start out as
+    /// `base`, which is only needed if the first change is incremental.
+    pub fn edit(
+        &self,
+        id: FileId,
+        base: Option<String>,
+        changes: Vec<TextChange>,
+    ) -> Result<(), OverlayError> {
+        let mut overlay = self.0.lock();
+        let mut lines = overlay
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| Lines::new(base.unwrap_or_default()));
+
+        for TextChange { range, text } in changes {
+            match range {
+                Some(TextRange { start, end }) => {
+                    let start =
+                        position_to_byte(&lines, start).ok_or(OverlayError::InvalidRange)?;
+                    let end = position_to_byte(&lines, end).ok_or(OverlayError::InvalidRange)?;
+                    if start > end {
+                        return Err(OverlayError::InvalidRange);
+                    }
+
+                    lines.edit(start..end, &text);
+                }
+                None => {
+                    lines.replace(&text);
+                }
+            }
+        }
+
+        overlay.insert(id, lines);
+
+        Ok(())
+    }
+
+    pub fn remove(&self, id: FileId) -> bool {
+        self.0.lock().remove(&id).is_some()
+    }
+}
+
+fn position_to_byte(lines: &Lines<String>, position: TextPosition) -> Option<usize> {
+    let range = lines.line_to_range(position.line)?;
+    let line = lines.text()[range.clone()].trim_end_matches(['\n', '\r']);
+    let line_start = lines.byte_to_utf16(range.start)?;
+
+    // Like in the language server protocol, characters past the end of the
+    // line refer to the end of the line.
+    let byte = lines
+        .utf16_to_byte(line_start + position.character)
+        .unwrap_or(usize::MAX);
+
+    Some(byte.min(range.start + line.len()))
+}
+
 pub struct SystemWorld<S> {
     resources: Arc<Resources>,
+    files: Arc<dyn FileSource>,
     package_storage: PackageStorage<S>,
     slots: Arc<Mutex<HashMap<FileId, FileSlot>>>,
+    overlay: Overlay,
+    /// Once cancelled, reads fail so that the compile stops early and leaves
+    /// the slots to the compile which replaced it.
+    stale: CancellationToken,
     state: State,
     dependencies: Arc<Mutex<HashSet<FileId>>>,
 }
@@ -74,20 +224,29 @@ pub struct SystemWorld<S> {
 impl<S> SystemWorld<S> {
     pub fn new(
         resources: Arc<Resources>,
+        files: Arc<dyn FileSource>,
         package_storage: PackageStorage<S>,
         slots: Arc<Mutex<HashMap<FileId, FileSlot>>>,
+        overlay: Overlay,
+        stale: CancellationToken,
         main_id: FileId,
     ) -> Self {
         // let virtual_path = VirtualPath::within_root(path, &resources.root)
         //     .ok_or(SystemWorldCreationError::PathOutsideRoot)?;
         // let main_id = FileId::new(None, virtual_path);
-        let state = State::new(main_id, UtcDateTime::now());
+        let time = resources
+            .creation_timestamp
+            .unwrap_or_else(UtcDateTime::now);
+        let state = State::new(main_id, time);
         let dependencies = Arc::new(Mutex::new(HashSet::new()));
 
         SystemWorld {
             resources,
+            files,
             package_storage,
             slots,
+            overlay,
+            stale,
             state,
             dependencies,
         }
@@ -104,6 +263,52 @@ impl<S> SystemWorld<S> {
     }
 }
 
+impl<S> SystemWorld<S>
+where
+    S: PackageService,
+    PackageError: From<S::GetIndexServiceError>,
+    PackageError: From<S::GetPackageServiceError>,
+    S::GetPackageBuffer: Buf,
+{
+    /// Gets a file from its slot, reading it if this compile hasn't yet. The
+    /// slots aren't held while reading, so that a slow read doesn't hold up
+    /// the build service invalidating them.
+    fn access<T>(
+        &self,
+        id: FileId,
+        cached: impl Fn(&FileSlot) -> Option<FileResult<T>>,
+        load: impl Fn(&mut FileSlot, FileResult<Vec<u8>>) -> FileResult<T>,
+    ) -> FileResult<T> {
+        let generation = {
+            let mut slots = self.slots.lock();
+            let slot = slots.entry(id).or_insert_with(|| FileSlot::new(id));
+            if let Some(result) = cached(slot) {
+                return result;
+            }
+
+            slot.generation
+        };
+
+        let data = read(
+            &self.resources.root,
+            self.files.as_ref(),
+            id,
+            &self.package_storage,
+            &self.overlay,
+        );
+
+        let mut slots = self.slots.lock();
+        let slot = slots.entry(id).or_insert_with(|| FileSlot::new(id));
+        // The file changed while it was being read, the data may be outdated
+        // and mustn't be cached for the compile which follows
+        if slot.generation != generation {
+            return load(&mut FileSlot::new(id), data);
+        }
+
+        load(slot, data)
+    }
+}
+
 impl<S> World for SystemWorld<S>
 where
     S: Send + Sync,
@@ -125,25 +330,25 @@ where
     }
 
     fn source(&self, id: FileId) -> FileResult<Source> {
+        if self.stale.is_cancelled() {
+            return Err(FileError::Other(Some("compile abandoned".into())));
+        }
         if id != self.state.main_id {
             self.dependencies.lock().insert(id);
         }
 
-        let mut slots = self.slots.lock();
-        let slot = slots.entry(id).or_insert_with(|| FileSlot::new(id));
-
-        slot.source(&self.resources.root, id, &self.package_storage)
+        self.access(id, FileSlot::cached_source, FileSlot::load_source)
     }
 
     fn file(&self, id: FileId) -> FileResult<Bytes> {
+        if self.stale.is_cancelled() {
+            return Err(FileError::Other(Some("compile abandoned".into())));
+        }
         if id != self.state.main_id {
             self.dependencies.lock().insert(id);
         }
 
-        let mut slots = self.slots.lock();
-        let slot = slots.entry(id).or_insert_with(|| FileSlot::new(id));
-
-        slot.file(&self.resources.root, id, &self.package_storage)
+        self.access(id, FileSlot::cached_file, FileSlot::load_file)
     }
 
     fn font(&self, index: usize) -> Option<Font> {
@@ -162,6 +367,8 @@ pub struct FileSlot {
     id: FileId,
     source: SlotCell<Source>,
     file: SlotCell<Bytes>,
+    /// Bumped on every reset, to tell reads which raced with one.
+    generation: u64,
 }
 
 impl FileSlot {
@@ -170,6 +377,7 @@ impl FileSlot {
             id,
             file: SlotCell::new(),
             source: SlotCell::new(),
+            generation: 0,
         }
     }
 
@@ -180,51 +388,32 @@ impl FileSlot {
     pub fn reset(&mut self) {
         self.source.reset();
         self.file.reset();
+        self.generation += 1;
     }
 
-    pub fn source<S>(
-        &mut self,
-        root: &Path,
-        file_id: FileId,
-        package_storage: &PackageStorage<S>,
-    ) -> FileResult<Source>
-    where
-        S: PackageService,
-        PackageError: From<S::GetIndexServiceError>,
-        PackageError: From<S::GetPackageServiceError>,
-        S::GetPackageBuffer: Buf,
-    {
-        self.source.get_or_init(
-            || read(root, file_id, package_storage),
-            |data, previous| {
-                let text = decode_utf8(&data)?;
-                if let Some(mut previous) = previous {
-                    previous.replace(text);
-
-                    Ok(previous)
-                } else {
-                    Ok(Source::new(self.id, text.into()))
-                }
-            },
-        )
+    fn cached_source(&self) -> Option<FileResult<Source>> {
+        self.source.cached()
     }
 
-    pub fn file<S>(
-        &mut self,
-        root: &Path,
-        file_id: FileId,
-        package_storage: &PackageStorage<S>,
-    ) -> FileResult<Bytes>
-    where
-        S: PackageService,
-        PackageError: From<S::GetIndexServiceError>,
-        PackageError: From<S::GetPackageServiceError>,
-        S::GetPackageBuffer: Buf,
-    {
-        self.file.get_or_init(
-            || read(root, file_id, package_storage),
-            |data, _| Ok(Bytes::new(data)),
-        )
+    fn load_source(&mut self, data: FileResult<Vec<u8>>) -> FileResult<Source> {
+        self.source.load(data, |data, previous| {
+            let text = decode_utf8(&data)?;
+            if let Some(mut previous) = previous {
+                previous.replace(text);
+
+                Ok(previous)
+            } else {
+                Ok(Source::new(self.id, text.into()))
+            }
+        })
+    }
+
+    fn cached_file(&self) -> Option<FileResult<Bytes>> {
+        self.file.cached()
+    }
+
+    fn load_file(&mut self, data: FileResult<Vec<u8>>) -> FileResult<Bytes> {
+        self.file.load(data, |data, _| Ok(Bytes::new(data)))
     }
 }
 
@@ -256,20 +445,23 @@ impl<T: Clone> SlotCell<T> {
     //     self.data.as_ref()
     // }
 
-    fn get_or_init(
+    /// The data if it was already read in this compilation.
+    fn cached(&self) -> Option<FileResult<T>> {
+        if self.accessed {
+            self.data.clone()
+        } else {
+            None
+        }
+    }
+
+    fn load(
         &mut self,
-        load: impl FnOnce() -> FileResult<Vec<u8>>,
+        result: FileResult<Vec<u8>>,
         f: impl FnOnce(Vec<u8>, Option<T>) -> FileResult<T>,
     ) -> FileResult<T> {
-        // If we accessed the file already in this compilation, retrieve it.
-        if mem::replace(&mut self.accessed, true)
-            && let Some(data) = &self.data
-        {
-            return data.clone();
-        }
+        self.accessed = true;
 
-        // Read and hash the file.
-        let result = load();
+        // Hash the file.
         let fingerprint = typst::utils::hash128(&result);
 
         // If the file contents didn't change, yield the old processed data.
@@ -309,20 +501,38 @@ where
     id.vpath().resolve(root).ok_or(FileError::AccessDenied)
 }
 
-fn read<S>(root: &Path, id: FileId, package_storage: &PackageStorage<S>) -> FileResult<Vec<u8>>
+fn read<S>(
+    root: &Path,
+    files: &dyn FileSource,
+    id: FileId,
+    package_storage: &PackageStorage<S>,
+    overlay: &Overlay,
+) -> FileResult<Vec<u8>>
 where
     S: PackageService,
     PackageError: From<S::GetIndexServiceError>,
     PackageError: From<S::GetPackageServiceError>,
     S::GetPackageBuffer: Buf,
 {
+    if let Some(data) = overlay.get(id) {
+        return Ok(data);
+    }
+
     let path = system_path(root, id, package_storage)?;
     let on_error = |e| FileError::from_io(e, &path);
 
-    if fs::metadata(&path).map_err(on_error)?.is_dir() {
-        Err(FileError::IsDirectory)
+    // Packages are always unpacked to disk
+    if id.package().is_some() {
+        if fs::metadata(&path).map_err(on_error)?.is_dir() {
+            Err(FileError::IsDirectory)
+        } else {
+            fs::read(&path).map_err(on_error)
+        }
     } else {
-        fs::read(&path).map_err(on_error)
+        files.read(&path).map_err(|error| match error.kind() {
+            io::ErrorKind::IsADirectory => FileError::IsDirectory,
+            _ => on_error(error),
+        })
     }
 }
 
@@ -332,3 +542,116 @@ fn decode_utf8(buf: &[u8]) -> FileResult<&str> {
         buf.strip_prefix(b"\xef\xbb\xbf").unwrap_or(buf),
     )?)
 }
+
+#[cfg(test)]
+mod tests {
+    use typst::syntax::VirtualPath;
+
+    use super::*;
+
+    fn position(line: usize, character: usize) -> TextPosition {
+        TextPosition { line, character }
+    }
+
+    fn change(start: (usize, usize), end: (usize, usize), text: &str) -> TextChange {
+        TextChange {
+            range: Some(TextRange {
+                start: position(start.0, start.1),
+                end: position(end.0, end.1),
+            }),
+            text: text.into(),
+        }
+    }
+
+    fn file() -> FileId {
+        FileId::new(None, VirtualPath::new("notes/a.typ"))
+    }
+
+    #[test]
+    fn positions_count_utf16_code_units() {
+        // "é" is one code unit and two bytes, "𝄞" two code units and four bytes
+        let lines = Lines::new("aé𝄞b\r\nsecond\n".to_string());
+
+        assert_eq!(position_to_byte(&lines, position(0, 0)), Some(0));
+        assert_eq!(position_to_byte(&lines, position(0, 2)), Some(3));
+        assert_eq!(position_to_byte(&lines, position(0, 4)), Some(7));
+        assert_eq!(position_to_byte(&lines, position(0, 5)), Some(8));
+        assert_eq!(position_to_byte(&lines, position(1, 3)), Some(13));
+    }
+
+    #[test]
+    fn positions_past_the_line_end_clamp() {
+        let lines = Lines::new("ab\r\ncd\n".to_string());
+
+        // Before the line break, not within it or on the next line
+        assert_eq!(position_to_byte(&lines, position(0, 3)), Some(2));
+        assert_eq!(position_to_byte(&lines, position(0, 100)), Some(2));
+        assert_eq!(position_to_byte(&lines, position(1, 100)), Some(6));
+        // The empty line after the last line break
+        assert_eq!(position_to_byte(&lines, position(2, 0)), Some(7));
+        assert_eq!(position_to_byte(&lines, position(3, 0)), None);
+    }
+
+    #[test]
+    fn edits_apply_in_order() {
+        let overlay = Overlay::default();
+
+        overlay
+            .edit(
+                file(),
+                Some("= Title\n𝄞 text\n".into()),
+                vec![
+                    change((1, 3), (1, 7), "notes"),
+                    change((0, 2), (0, 7), "Heading"),
+                    change((2, 0), (2, 0), "more\n"),
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(
+            overlay.get(file()).unwrap(),
+            "= Heading\n𝄞 notes\nmore\n".as_bytes()
+        );
+
+        // Once in the overlay, the base isn't needed anymore
+        overlay
+            .edit(file(), None, vec![change((2, 0), (3, 0), "")])
+            .unwrap();
+        assert_eq!(
+            overlay.get(file()).unwrap(),
+            "= Heading\n𝄞 notes\n".as_bytes()
+        );
+    }
+
+    #[test]
+    fn full_changes_replace_the_buffer() {
+        let overlay = Overlay::default();
+        let replace = TextChange {
+            range: None,
+            text: "new".into(),
+        };
+
+        overlay.edit(file(), None, vec![replace]).unwrap();
+
+        assert_eq!(overlay.get(file()).unwrap(), b"new");
+        assert!(overlay.remove(file()));
+        assert!(!overlay.contains(file()));
+    }
+
+    #[test]
+    fn invalid_ranges_leave_the_buffer_alone() {
+        let overlay = Overlay::default();
+        overlay
+            .edit(file(), Some("abc\n".into()), Vec::new())
+            .unwrap();
+
+        for invalid in [change((0, 2), (0, 1), ""), change((5, 0), (5, 0), "")] {
+            assert!(matches!(
+                overlay.edit(file(), None, vec![change((0, 0), (0, 1), "x"), invalid]),
+                Err(OverlayError::InvalidRange)
+            ));
+        }
+
+        assert_eq!(overlay.get(file()).unwrap(), b"abc\n");
+    }
+}
@@ -0,0 +1,729 @@
+//! Runs the build service against an in-memory vault, scripting file events
+//! and checking the messages broadcast to clients.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use flate2::{Compression, write::GzEncoder};
+use notify_debouncer_full::{
+    DebounceEventResult, DebouncedEvent,
+    notify::{
+        Event, EventKind,
+        event::{CreateKind, DataChange, ModifyKind, RemoveKind, RenameMode},
+    },
+};
+use parking_lot::{Condvar, Mutex};
+use phelps::{
+    app::{DEFAULT_EDITOR_ADDRESS, DEFAULT_HTTP_ADDRESS},
+    build_service::{BuildInputs, BuildService, BuildServiceHandle, Reconfiguration},
+    config::{Config, ConfigOverrides, FontConfig, NoteConfig, SourceConfig, SourceGlobs},
+    file_source::{FileSource, MemoryFileSource},
+    notes_service::{Initialize, NoteMessage, NoteUpdate, NotesServiceHandle},
+    package::{GetPackageError, Package, PackageService},
+};
+use scraper::{Html, Selector};
+use tempfile::TempDir;
+use tokio::{
+    runtime::Handle,
+    sync::{broadcast, mpsc},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+use typst::{diag::PackageError, syntax::package::PackageSpec};
+use uuid::Uuid;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+const HTML_WARNING: &str = "html export is under active development and incomplete";
+
+const A: Uuid = Uuid::from_u128(0xa);
+const B: Uuid = Uuid::from_u128(0xb);
+
+/// Serves packages from memory instead of the registry.
+#[derive(Clone, Default)]
+struct TestPackages(Arc<HashMap<String, Bytes>>);
+
+impl TestPackages {
+    /// A registry with a single package made of the given files.
+    fn with_package(name: &str, version: &str, files: &[(&str, &str)]) -> Self {
+        let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+
+        let mut encoder = archive.into_inner().unwrap();
+        encoder.flush().unwrap();
+        let buffer = Bytes::from(encoder.finish().unwrap());
+
+        Self(Arc::new(HashMap::from([(
+            format!("{name}-{version}"),
+            buffer,
+        )])))
+    }
+}
+
+impl PackageService for TestPackages {
+    type GetIndexServiceError = PackageError;
+
+    async fn get_index(&self) -> Result<Vec<Package>, Self::GetIndexServiceError> {
+        Ok(Vec::new())
+    }
+
+    type GetPackageServiceError = PackageError;
+    type GetPackageBuffer = Bytes;
+
+    async fn get_package(
+        &self,
+        specification: PackageSpec,
+    ) -> Result<Result<Self::GetPackageBuffer, GetPackageError>, Self::GetPackageServiceError> {
+        let key = format!("{}-{}", specification.name, specification.version);
+
+        Ok(self.0.get(&key).cloned().ok_or(GetPackageError::NotFound))
+    }
+}
+
+/// Holds reads of a file while closed, so that a test can pause a compile at a
+/// known point.
+#[derive(Clone, Default)]
+struct Gate(Arc<(Mutex<GateState>, Condvar)>);
+
+#[derive(Default)]
+struct GateState {
+    closed: Option<PathBuf>,
+    held: usize,
+}
+
+impl Gate {
+    fn close(&self, path: PathBuf) {
+        self.0.0.lock().closed = Some(path);
+    }
+
+    fn open(&self) {
+        self.0.0.lock().closed = None;
+        self.0.1.notify_all();
+    }
+
+    fn pass(&self, path: &Path) {
+        let (state, opened) = &*self.0;
+        let mut state = state.lock();
+        if state.closed.as_deref() != Some(path) {
+            return;
+        }
+
+        state.held += 1;
+        while state.closed.as_deref() == Some(path) {
+            opened.wait(&mut state);
+        }
+        state.held -= 1;
+    }
+
+    /// Waits until a read is being held.
+    async fn holding(&self) {
+        timeout(TIMEOUT, async {
+            while self.0.0.lock().held == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("no read was held");
+    }
+}
+
+struct GatedFiles {
+    files: MemoryFileSource,
+    gate: Gate,
+}
+
+impl FileSource for GatedFiles {
+    /// Returns the contents from before being held.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let contents = self.files.read(path);
+        self.gate.pass(path);
+
+        contents
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.exists(path)
+    }
+
+    fn walk(&self, directory: &Path, descend: &dyn Fn(&Path) -> bool) -> Vec<PathBuf> {
+        self.files.walk(directory, descend)
+    }
+}
+
+/// A project whose files only exist in memory. The build directory and
+/// package cache are temporary directories on disk.
+struct Vault {
+    root: PathBuf,
+    files: MemoryFileSource,
+    gate: Gate,
+    events: mpsc::Sender<DebounceEventResult>,
+    notes_service: NotesServiceHandle,
+    build_service: BuildServiceHandle,
+    cancel: CancellationToken,
+    _directory: TempDir,
+}
+
+impl Vault {
+    /// Builds the given files and waits for the initial build to finish.
+    async fn start(files: &[(&str, &str)], packages: TestPackages) -> Self {
+        let directory = TempDir::new().unwrap();
+        // Doesn't exist on disk, so nothing can be read from there by accident
+        let root = directory.path().join("vault");
+        let build_subdirectory = directory.path().join("build");
+
+        let source = MemoryFileSource::default();
+        for (path, contents) in files {
+            source.write(root.join(path), *contents);
+        }
+
+        let cancel = CancellationToken::new();
+        let (notes_service, notes) = NotesServiceHandle::build(
+            cancel.clone(),
+            build_subdirectory.clone(),
+            root.clone(),
+            Uuid::nil(),
+        );
+        let config = Config {
+            data_directory: directory.path().join("data"),
+            cache_directory: directory.path().join("cache"),
+            project_directory: root.clone(),
+            notes_subdirectory: root.join("notes"),
+            extra_directories: Vec::new(),
+            build_subdirectory,
+            http_address: DEFAULT_HTTP_ADDRESS,
+            editor_address: DEFAULT_EDITOR_ADDRESS,
+            default_note: Uuid::nil(),
+            fonts: FontConfig {
+                directories: Vec::new(),
+                system: false,
+                embedded: true,
+            },
+            inputs: BTreeMap::new(),
+            creation_timestamp: None,
+            notes: NoteConfig::default(),
+            sources: SourceGlobs::try_build(SourceConfig::default()).unwrap(),
+            path: None,
+            overrides: ConfigOverrides::default(),
+        };
+        let gate = Gate::default();
+        let (sender, events) = mpsc::channel(16);
+        let (build_service_handle, build_service) = BuildService::new(
+            config,
+            Handle::current(),
+            notes_service.clone(),
+            Arc::default(),
+            cancel.clone(),
+            BuildInputs {
+                files: Arc::new(GatedFiles {
+                    files: source.clone(),
+                    gate: gate.clone(),
+                }),
+                events,
+                watcher: None,
+                package_service: packages,
+            },
+        );
+
+        tokio::spawn(notes.run());
+        tokio::spawn(build_service.run());
+
+        let finished = notes_service.get_build_finished().await.unwrap();
+        timeout(TIMEOUT, finished.wait())
+            .await
+            .expect("initial build didn't finish");
+
+        Self {
+            root,
+            files: source,
+            gate,
+            events: sender,
+            notes_service,
+            build_service: build_service_handle,
+            cancel,
+            _directory: directory,
+        }
+    }
+
+    async fn subscribe(&self) -> (Initialize, Updates) {
+        let (initialize, receiver) = self.notes_service.subscribe().await.unwrap();
+
+        (initialize, Updates(receiver))
+    }
+
+    async fn create(&self, path: &str, contents: &str) {
+        self.files.write(self.root.join(path), contents);
+        self.send(EventKind::Create(CreateKind::File), path).await;
+    }
+
+    async fn modify(&self, path: &str, contents: &str) {
+        self.files.write(self.root.join(path), contents);
+        self.send(
+            EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+            path,
+        )
+        .await;
+    }
+
+    /// Replaces a file by renaming another one over it, like most editors
+    /// save.
+    async fn atomic_save(&self, path: &str, contents: &str) {
+        self.files.write(self.root.join(path), contents);
+        self.send(EventKind::Modify(ModifyKind::Name(RenameMode::To)), path)
+            .await;
+    }
+
+    async fn remove(&self, path: &str) {
+        assert!(self.files.remove(&self.root.join(path)));
+        self.send(EventKind::Remove(RemoveKind::File), path).await;
+    }
+
+    async fn send(&self, kind: EventKind, path: &str) {
+        let event = Event::new(kind).add_path(self.root.join(path));
+
+        self.events
+            .send(Ok(vec![DebouncedEvent::new(event, Instant::now())]))
+            .await
+            .unwrap();
+    }
+
+    /// Waits until the build service has handled every event sent so far.
+    async fn handled(&self) {
+        // Events are taken in order, and errors are ignored
+        self.events.send(Err(Vec::new())).await.unwrap();
+        timeout(TIMEOUT, async {
+            while self.events.capacity() < self.events.max_capacity() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("events weren't handled");
+    }
+}
+
+impl Drop for Vault {
+    fn drop(&mut self) {
+        self.gate.open();
+        self.cancel.cancel();
+    }
+}
+
+struct Updates(broadcast::Receiver<NoteMessage>);
+
+impl Updates {
+    async fn next(&mut self) -> NoteMessage {
+        timeout(TIMEOUT, self.0.recv())
+            .await
+            .expect("no message was broadcast")
+            .unwrap()
+    }
+}
+
+fn note(id: Uuid, title: &str) -> String {
+    format!("= {title} <note:{id}>\nText.\n")
+}
+
+/// The update for a note without links, metadata or sub-headings.
+fn update(id: Uuid, title: &str) -> NoteUpdate {
+    NoteUpdate {
+        title: title.into(),
+        id,
+        links: Vec::new(),
+        parent: None,
+        children: Vec::new(),
+        metadata: Default::default(),
+        outline: Vec::new(),
+        warnings: vec![HTML_WARNING.into()],
+        errors: Vec::new(),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn create_modify_delete() {
+    let vault = Vault::start(&[], TestPackages::default()).await;
+    let (initialize, mut updates) = vault.subscribe().await;
+    assert!(initialize.titles.is_empty());
+
+    vault.create("notes/a.typ", &note(A, "First")).await;
+    assert_eq!(
+        updates.next().await,
+        NoteMessage::Update(vec![update(A, "First")])
+    );
+
+    vault.modify("notes/a.typ", &note(A, "Renamed")).await;
+    assert_eq!(
+        updates.next().await,
+        NoteMessage::Update(vec![update(A, "Renamed")])
+    );
+
+    vault.remove("notes/a.typ").await;
+    assert_eq!(updates.next().await, NoteMessage::Remove(vec![A]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn atomic_save_rebuilds() {
+    let vault = Vault::start(
+        &[("notes/a.typ", &note(A, "First"))],
+        TestPackages::default(),
+    )
+    .await;
+    let (initialize, mut updates) = vault.subscribe().await;
+    assert_eq!(initialize.titles, HashMap::from([(A, "First".into())]));
+
+    vault.atomic_save("notes/a.typ", &note(A, "Saved")).await;
+    assert_eq!(
+        updates.next().await,
+        NoteMessage::Update(vec![update(A, "Saved")])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dependency_change_rebuilds_dependents() {
+    let a =
+        "#let data = json(\"/data.json\")\n= #data.title <note:0000000000000000000000000000000a>\n";
+    let b = "#import \"/template.typ\": greeting\n= #greeting <note:0000000000000000000000000000000b>\n";
+    let vault = Vault::start(
+        &[
+            ("notes/a.typ", a),
+            ("notes/b.typ", b),
+            ("data.json", r#"{ "title": "Data" }"#),
+            ("template.typ", "#let greeting = \"Hello\""),
+        ],
+        TestPackages::default(),
+    )
+    .await;
+    let (initialize, mut updates) = vault.subscribe().await;
+    assert_eq!(
+        initialize.titles,
+        HashMap::from([(A, "Data".into()), (B, "Hello".into())])
+    );
+
+    // Only the file reading the data is rebuilt
+    vault.modify("data.json", r#"{ "title": "Changed" }"#).await;
+    assert_eq!(
+        updates.next().await,
+        NoteMessage::Update(vec![update(A, "Changed")])
+    );
+
+    // A missing import is reported on the note, and fixed once it's back
+    vault.remove("template.typ").await;
+    let NoteMessage::Update(failed) = updates.next().await else {
+        panic!("expected an update");
+    };
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].id, B);
+    assert_eq!(failed[0].title, "Hello");
+    assert!(!failed[0].errors.is_empty());
+
+    vault
+        .create("template.typ", "#let greeting = \"Welcome\"")
+        .await;
+    assert_eq!(
+        updates.next().await,
+        NoteMessage::Update(vec![update(B, "Welcome")])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn packages_come_from_the_package_service() {
+    let packages = TestPackages::with_package(
+        "greet",
+        "0.1.0",
+        &[
+            (
+                "typst.toml",
+                "[package]\nname = \"greet\"\nversion = \"0.1.0\"\nentrypoint = \"lib.typ\"\n",
+            ),
+            ("lib.typ", "#let greeting = \"Packaged\""),
+        ],
+    );
+    let a = "#import \"@preview/greet:0.1.0\": greeting\n= #greeting <note:0000000000000000000000000000000a>\n";
+    let vault = Vault::start(&[("notes/a.typ", a)], packages).await;
+    let (initialize, _) = vault.subscribe().await;
+
+    assert_eq!(initialize.titles, HashMap::from([(A, "Packaged".into())]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn missing_source_is_built_once_created() {
+    let a = "#import \"b.typ\": name\n= #name <note:0000000000000000000000000000000a>\n";
+    let vault = Vault::start(&[("notes/a.typ", a)], TestPackages::default()).await;
+    let (initialize, mut updates) = vault.subscribe().await;
+    assert!(initialize.titles.is_empty());
+
+    vault
+        .create(
+            "notes/b.typ",
+            "#let name = \"Imported\"\n= Bee <note:0000000000000000000000000000000b>\nText.\n",
+        )
+        .await;
+    let NoteMessage::Update(mut notes) = updates.next().await else {
+        panic!("expected an update");
+    };
+    notes.sort_by_key(|note| note.id);
+    assert_eq!(notes, vec![update(A, "Imported"), update(B, "Bee")]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bibliography_changes_rebuild_citing_notes() {
+    let a =
+        "= Cited <note:0000000000000000000000000000000a>\n@knuth\n#bibliography(\"/refs.bib\")\n";
+    let bibliography = |title: &str| {
+        format!(
+            "@book{{knuth, title = {{{title}}}, author = {{Knuth, Donald}}, year = {{1968}}}}\n"
+        )
+    };
+    let vault = Vault::start(
+        &[
+            ("notes/a.typ", a),
+            ("refs.bib", &bibliography("Fundamental Algorithms")),
+        ],
+        TestPackages::default(),
+    )
+    .await;
+    let (initialize, mut updates) = vault.subscribe().await;
+    assert_eq!(initialize.titles, HashMap::from([(A, "Cited".into())]));
+
+    vault
+        .modify("refs.bib", &bibliography("Sorting and Searching"))
+        .await;
+    let NoteMessage::Update(notes) = updates.next().await else {
+        panic!("expected an update");
+    };
+    assert_eq!(notes.len(), 1);
+    assert_eq!(notes[0].id, A);
+    assert!(notes[0].errors.is_empty());
+
+    vault.remove("refs.bib").await;
+    let NoteMessage::Update(failed) = updates.next().await else {
+        panic!("expected an update");
+    };
+    assert_eq!(failed[0].id, A);
+    assert!(!failed[0].errors.is_empty());
+
+    vault
+        .create("refs.bib", &bibliography("Seminumerical Algorithms"))
+        .await;
+    let NoteMessage::Update(fixed) = updates.next().await else {
+        panic!("expected an update");
+    };
+    assert_eq!(fixed[0].id, A);
+    assert!(fixed[0].errors.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn image_changes_rebuild_notes_showing_them() {
+    let a = "= Pictured <note:0000000000000000000000000000000a>\n#image(\"/figure.svg\")\n";
+    let svg = |fill: &str| {
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"10\" height=\"10\"><rect width=\"10\" height=\"10\" fill=\"{fill}\"/></svg>"
+        )
+    };
+    let vault = Vault::start(
+        &[("notes/a.typ", a), ("figure.svg", &svg("red"))],
+        TestPackages::default(),
+    )
+    .await;
+    let (initialize, mut updates) = vault.subscribe().await;
+    assert_eq!(initialize.titles, HashMap::from([(A, "Pictured".into())]));
+
+    vault.modify("figure.svg", &svg("blue")).await;
+    assert_eq!(
+        updates.next().await,
+        NoteMessage::Update(vec![update(A, "Pictured")])
+    );
+
+    vault.remove("figure.svg").await;
+    let NoteMessage::Update(failed) = updates.next().await else {
+        panic!("expected an update");
+    };
+    assert_eq!(failed[0].id, A);
+    assert!(!failed[0].errors.is_empty());
+
+    vault.create("figure.svg", &svg("green")).await;
+    assert_eq!(
+        updates.next().await,
+        NoteMessage::Update(vec![update(A, "Pictured")])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn changes_during_a_compile_are_coalesced() {
+    let a =
+        "#let data = json(\"/data.json\")\n= #data.title <note:0000000000000000000000000000000a>\n";
+    let vault = Vault::start(
+        &[("notes/a.typ", a), ("data.json", r#"{ "title": "Data" }"#)],
+        TestPackages::default(),
+    )
+    .await;
+    let (_, mut updates) = vault.subscribe().await;
+
+    // The compile of the first change is held while reading the data
+    vault.gate.close(vault.root.join("data.json"));
+    vault.modify("data.json", r#"{ "title": "First" }"#).await;
+    vault.gate.holding().await;
+
+    // Abandoned before the held compile gets to finish
+    vault.modify("data.json", r#"{ "title": "Second" }"#).await;
+    vault.handled().await;
+    vault.gate.open();
+
+    // The abandoned compile is never reported to clients
+    assert_eq!(
+        updates.next().await,
+        NoteMessage::Update(vec![update(A, "Second")])
+    );
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(updates.0.try_recv().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reconfiguring_rescans_sources_and_rebuilds_notes() {
+    let vault = Vault::start(
+        &[
+            ("notes/a.typ", &note(A, "First")),
+            ("drafts/b.typ", &note(B, "Draft")),
+        ],
+        TestPackages::default(),
+    )
+    .await;
+    let (initialize, mut updates) = vault.subscribe().await;
+    assert_eq!(initialize.titles, HashMap::from([(A, "First".into())]));
+
+    let directories = vec![vault.root.join("notes"), vault.root.join("drafts")];
+    let sources = |exclude: &[&str]| {
+        SourceGlobs::try_build(SourceConfig {
+            include: Vec::new(),
+            exclude: exclude.iter().map(|glob| glob.to_string()).collect(),
+        })
+        .unwrap()
+    };
+
+    // A new source directory is scanned for notes
+    vault
+        .build_service
+        .reconfigure(Reconfiguration {
+            sources: Some((directories.clone(), sources(&[]))),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        updates.next().await,
+        NoteMessage::Update(vec![update(B, "Draft")])
+    );
+
+    // Notes which are excluded now are removed
+    vault
+        .build_service
+        .reconfigure(Reconfiguration {
+            sources: Some((directories, sources(&["notes/**"]))),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(updates.next().await, NoteMessage::Remove(vec![A]));
+
+    // Notes come out differently, so the remaining ones are rebuilt
+    vault
+        .build_service
+        .reconfigure(Reconfiguration {
+            note_config: Some(NoteConfig {
+                level: 2,
+                ..NoteConfig::default()
+            }),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let NoteMessage::Update(notes) = updates.next().await else {
+        panic!("expected an update");
+    };
+    assert_eq!(notes.len(), 1);
+    assert_eq!(notes[0].id, B);
+
+    vault.notes_service.set_default_note(B).await.unwrap();
+    assert_eq!(updates.next().await, NoteMessage::DefaultNote(B));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ignore_file_changes_rescan_sources() {
+    let vault = Vault::start(
+        &[
+            ("notes/a.typ", &note(A, "First")),
+            ("notes/b.typ", &note(B, "Second")),
+        ],
+        TestPackages::default(),
+    )
+    .await;
+    let (_, mut updates) = vault.subscribe().await;
+
+    vault.create(".gitignore", "notes/b.typ\n").await;
+    assert_eq!(updates.next().await, NoteMessage::Remove(vec![B]));
+
+    vault.modify(".gitignore", "").await;
+    assert_eq!(
+        updates.next().await,
+        NoteMessage::Update(vec![update(B, "Second")])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn duplicate_titles_and_unlabelled_headings_build() {
+    let a = "= Same <note:0000000000000000000000000000000a>\nText.\n= Unlabelled\nMore.\n= Same <note:0000000000000000000000000000000b>\nText.\n";
+    let vault = Vault::start(&[("notes/a.typ", a)], TestPackages::default()).await;
+    let (initialize, _) = vault.subscribe().await;
+
+    assert_eq!(
+        initialize.titles,
+        HashMap::from([(A, "Same".into()), (B, "Same".into())])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transcluded_footnotes_are_merged() {
+    let a = "= Host <note:0000000000000000000000000000000a>\nHost#footnote[Host note.]\n\n#link(\"transclude://0000000000000000000000000000000b\")\n\n= Guest <note:0000000000000000000000000000000b>\nGuest#footnote[Shared.] <shared>\n\n= Third <note:0000000000000000000000000000000c>\nThird#footnote(<shared>)\n";
+    let vault = Vault::start(&[("notes/a.typ", a)], TestPackages::default()).await;
+    let content = async |id| {
+        vault
+            .notes_service
+            .get_note_content(id)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    };
+
+    // A single section, without the guest's ids which live on its own page
+    let host = content(A).await;
+    assert_eq!(host.matches(r#"role="doc-endnotes""#).count(), 1);
+    assert_eq!(host.matches(r#"id="shared""#).count(), 0);
+    assert_eq!(host.matches(r#"<sup>2</sup>"#).count(), 2);
+
+    // The shared footnote links back to the reference in this note
+    let third = Html::parse_fragment(&content(Uuid::from_u128(0xc)).await);
+    let backlink = third
+        .select(&Selector::parse(r#"a[role="doc-backlink"]"#).unwrap())
+        .next()
+        .and_then(|backlink| backlink.attr("href"))
+        .unwrap();
+    let reference = third
+        .select(&Selector::parse(r#"a[role="doc-noteref"]"#).unwrap())
+        .next()
+        .and_then(|reference| reference.attr("id"))
+        .unwrap();
+    assert_eq!(backlink, format!("#{reference}"));
+}
@@ -31,10 +31,15 @@ export function NoteContent({
       if (!anchor) return;
 
       event.preventDefault();
-      const href = (anchor as HTMLAnchorElement).href;
-      const [, id] = href.split("//");
+      const href = anchor.getAttribute("href")!;
+      const [, rest] = href.split("//");
+      const [noteId, fragment] = rest.split("#");
 
-      navigate(`/note/${id}`);
+      if (noteId === id && fragment) {
+        document.getElementById(fragment)?.scrollIntoView();
+      } else {
+        navigate(fragment ? `/note/${noteId}#${fragment}` : `/note/${noteId}`);
+      }
     }
 
     (container as Element).addEventListener("click", handleClick);
@@ -42,7 +47,14 @@ export function NoteContent({
     return () => {
       (container as Element).removeEventListener("click", handleClick);
     };
-  }, [navigate]);
+  }, [id, navigate]);
+
+  useEffect(() => {
+    const fragment = decodeURIComponent(window.location.hash.slice(1));
+    if (!html || !fragment) return;
+
+    document.getElementById(fragment)?.scrollIntoView();
+  }, [id, html]);
 
   useEffect(() => {
     if (status === "dirty" || status === "empty") {
@@ -53,6 +53,10 @@ export type Action =
       type: "setContent";
       id: string;
       html: string;
+    }
+  | {
+      type: "setDefaultNote";
+      id: string;
     };
 
 export function reducer(state: State, action: Action): State {
@@ -163,6 +167,12 @@ export function reducer(state: State, action: Action): State {
         content: newContent,
       };
     }
+    case "setDefaultNote": {
+      return {
+        ...state,
+        defaultNote: action.id,
+      };
+    }
   }
 }
 
@@ -51,6 +51,14 @@ export function handleSocketMessage(
         }
         break;
       }
+      case "default_note": {
+        if (message.content) {
+          dispatch({ type: "setDefaultNote", id: message.content });
+        } else {
+          throw new Error("Missing content in default_note message");
+        }
+        break;
+      }
       default: {
         throw new Error(`Unknown message tag: ${message.tag}`);
       }